@@ -1,7 +1,121 @@
+use std::{env, fs, path::PathBuf};
+
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+const SHN_LORESERVE: u16 = 0xff00;
+const SHF_EXECINSTR: u64 = 0x4;
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+/// (address, size, demangled name) of every function in a little endian ELF64 image, sorted by address
+fn functions(elf: &[u8]) -> Vec<(u64, u64, String)> {
+    assert!(
+        elf.starts_with(b"\x7fELF\x02\x01"),
+        "KSYMS_FROM isn't a little endian ELF64 file"
+    );
+    let shoff = u64_at(elf, 0x28) as usize;
+    let shentsize = u16_at(elf, 0x3a) as usize;
+    let shnum = u16_at(elf, 0x3c) as usize;
+    let section = |index: usize| &elf[shoff + index * shentsize..][..shentsize];
+    let mut symbols = Vec::new();
+    for symtab in (0..shnum).map(section) {
+        if u32_at(symtab, 4) != SHT_SYMTAB {
+            continue;
+        }
+        let strtab = section(u32_at(symtab, 40) as usize);
+        let strings = &elf[u64_at(strtab, 24) as usize..][..u64_at(strtab, 32) as usize];
+        let entries = &elf[u64_at(symtab, 24) as usize..][..u64_at(symtab, 32) as usize];
+        for entry in entries.chunks_exact(u64_at(symtab, 56) as usize) {
+            let kind = entry[4] & 0xf;
+            let shndx = u16_at(entry, 6);
+            let name = &strings[u32_at(entry, 0) as usize..];
+            let name = std::str::from_utf8(&name[..name.iter().position(|&b| b == 0).unwrap()])
+                .unwrap_or("");
+            // assembly labels like the trap vectors have no type, mapping symbols and local
+            // labels are skipped
+            let wanted = kind == STT_FUNC
+                || (kind == STT_NOTYPE && !name.starts_with(['$', '.', '_']) && !name.is_empty());
+            if !wanted || shndx == SHN_UNDEF || shndx >= SHN_LORESERVE {
+                continue;
+            }
+            // linker script symbols like `end` have no type either, but aren't in code
+            if u64_at(section(shndx as usize), 8) & SHF_EXECINSTR == 0 {
+                continue;
+            }
+            let addr = u64_at(entry, 8);
+            let size = u64_at(entry, 16);
+            symbols.push((
+                addr,
+                size,
+                kind,
+                format!("{:#}", rustc_demangle::demangle(name)),
+            ));
+        }
+    }
+    // functions before labels at the same address
+    symbols.sort_by_key(|&(addr, _, kind, _)| (addr, kind != STT_FUNC));
+    symbols.dedup_by_key(|(addr, ..)| *addr);
+    symbols
+        .into_iter()
+        .map(|(addr, size, _, name)| (addr, size, name))
+        .collect()
+}
+
+/// the table the kernel searches for backtraces (src/backtrace.rs): "KSYM", a u32 count, then per
+/// function its u64 address, u64 size, u32 name offset and u32 name length, then the names
+fn symbol_table(functions: &[(u64, u64, String)]) -> Vec<u8> {
+    let mut table = b"KSYM".to_vec();
+    table.extend((functions.len() as u32).to_le_bytes());
+    let mut names: Vec<u8> = Vec::new();
+    for (addr, size, name) in functions {
+        table.extend(addr.to_le_bytes());
+        table.extend(size.to_le_bytes());
+        table.extend((names.len() as u32).to_le_bytes());
+        table.extend((name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend(names);
+    table
+}
+
 fn main() {
-    // Use the linker script.
-    println!("cargo:rustc-link-arg=-Tsrc/script.ld");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    // Use the linker script, moved to the start of RAM when there is no firmware below us.
+    let script = fs::read_to_string("src/script.ld").unwrap();
+    let script = if env::var_os("CARGO_FEATURE_MMODE").is_some() {
+        script.replace("ORIGIN = 0x80200000", "ORIGIN = 0x80000000")
+    } else {
+        script
+    };
+    let out = out_dir.join("script.ld");
+    fs::write(&out, script).unwrap();
+    println!("cargo:rustc-link-arg=-T{}", out.display());
     println!("cargo:rerun-if-changed=src/script.ld");
     // Don't do any magic linker stuff.
     //println!("cargo:rustc-link-arg=--omagic");
+
+    // The symbol table for backtraces comes from an already linked kernel, build.sh links twice and
+    // points KSYMS_FROM at the first one. The table sits after all the code, so embedding it doesn't
+    // move any function. Without KSYMS_FROM backtraces are bare addresses.
+    println!("cargo:rerun-if-env-changed=KSYMS_FROM");
+    let functions = match env::var_os("KSYMS_FROM") {
+        Some(elf) => {
+            println!("cargo:rerun-if-changed={}", PathBuf::from(&elf).display());
+            functions(&fs::read(elf).expect("can't read KSYMS_FROM"))
+        }
+        None => Vec::new(),
+    };
+    fs::write(out_dir.join("ksyms.bin"), symbol_table(&functions)).unwrap();
 }
@@ -1,10 +1,15 @@
 #!/usr/bin/env bash
 #build the kernel binary
+#linked twice: the second link embeds the symbol table of the first, for panic backtraces (see build.rs)
 if [ -v rel ]; then
-    cargo build --release -Fno_log $@
-    cp target/riscv64imac-unknown-none-elf/release/oc2kernel .
+    profile=release
+    flags="--release -Fno_log"
 else
-    cargo build $@
-    cp target/riscv64imac-unknown-none-elf/debug/oc2kernel .
+    profile=debug
+    flags=""
 fi
+cargo build $flags $@ || exit 1
+cp target/riscv64imac-unknown-none-elf/$profile/oc2kernel .
+KSYMS_FROM=$PWD/oc2kernel cargo build $flags $@ || exit 1
+cp target/riscv64imac-unknown-none-elf/$profile/oc2kernel .
 llvm-objcopy -O binary oc2kernel kernel.bin
@@ -0,0 +1,12 @@
+#!/usr/bin/env bash
+#cargo runner, picks the firmware from where the kernel was linked
+#kernels built with --features mmode start at the bottom of RAM and own the machine, the rest boot under OpenSBI
+kernel=$1
+shift
+entry=$(llvm-readelf -h "$kernel" | awk '/Entry point/ {print $4}')
+if [ "$entry" = "0x80000000" ]; then
+    bios=none
+else
+    bios=default
+fi
+exec qemu-system-riscv64 -machine virt -cpu rv64 -m 128M -bios $bios -serial mon:stdio -nographic -kernel "$kernel" "$@"
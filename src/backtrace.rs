@@ -0,0 +1,158 @@
+// backtrace.rs
+// stack walking through the frame pointer chain (everything is built with frame pointers, see
+// .cargo/config.toml) and symbol lookup in the table build.rs embeds
+#![allow(dead_code)]
+use crate::{layout, println, sched};
+use core::{arch::asm, fmt};
+
+/// stop after this many frames, in case the chain loops back on itself without us noticing
+const MAX_FRAMES: usize = 64;
+/// magic, count
+const HEADER_SIZE: usize = 8;
+/// address, size, name offset, name length
+const ENTRY_SIZE: usize = 24;
+
+/// only ever read through `_ksyms_start` and `_ksyms_end`, so no instruction depends on its size
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+extern "C" {
+    fn _ksyms_start();
+    fn _ksyms_end();
+}
+
+/// the table build.rs made, empty when the kernel was built without `KSYMS_FROM`
+fn table() -> &'static [u8] {
+    let start = _ksyms_start as usize;
+    unsafe { core::slice::from_raw_parts(start as *const u8, _ksyms_end as usize - start) }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> usize {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize
+}
+
+/// a function from the symbol table
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub addr: usize,
+    /// 0 for assembly labels, which don't have a size
+    pub size: usize,
+}
+
+/// the function `addr` is in, if the table has one
+pub fn lookup(addr: usize) -> Option<Symbol> {
+    let table = table();
+    if table.len() < HEADER_SIZE || &table[..4] != b"KSYM" {
+        return None;
+    }
+    let count = u32_at(table, 4);
+    let names = HEADER_SIZE + count * ENTRY_SIZE;
+    let entry = |i: usize| {
+        let at = HEADER_SIZE + i * ENTRY_SIZE;
+        let name = &table[names + u32_at(table, at + 16)..][..u32_at(table, at + 20)];
+        Symbol {
+            name: core::str::from_utf8(name).unwrap_or("?"),
+            addr: u64_at(table, at),
+            size: u64_at(table, at + 8),
+        }
+    };
+    // the last function starting at or before `addr`
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(mid).addr <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let symbol = entry(low.checked_sub(1)?);
+    (symbol.size == 0 || addr < symbol.addr + symbol.size).then_some(symbol)
+}
+
+/// an address with the function it's in, `0x80201234 oc2kernel::entry+0x42`
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match lookup(self.0) {
+            Some(symbol) => write!(f, " {}+{:#x}", symbol.name, self.0 - symbol.addr),
+            None => write!(f, " ?"),
+        }
+    }
+}
+
+/// the stack `fp` points into, anything outside the kernel's own stacks has to be a task's
+fn stack_of(fp: usize) -> Option<layout::Region> {
+    let within = |region: &layout::Region| region.start < fp && fp <= region.end;
+    let known = core::iter::once(layout::init_stack())
+        .chain((0..layout::MAX_HARTS).filter_map(layout::hart_stack))
+        .chain((0..layout::MAX_HARTS).filter_map(layout::trap_stack))
+        .find(within);
+    known.or_else(|| sched::task_stack_of(fp))
+}
+
+/// call `f` with the return address of every frame from `fp` outwards. each frame stores the
+/// return address at `fp - 8` and the caller's `fp` at `fp - 16`
+pub fn walk(fp: usize, mut f: impl FnMut(usize)) {
+    let Some(stack) = stack_of(fp) else {
+        return;
+    };
+    let mut fp = fp;
+    for _ in 0..MAX_FRAMES {
+        if fp % 8 != 0 || fp < stack.start + 16 || fp > stack.end {
+            return;
+        }
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            return;
+        }
+        f(ra);
+        // frames only get older going up the stack
+        if prev <= fp {
+            return;
+        }
+        fp = prev;
+    }
+}
+
+/// print the frames from `fp` outwards, return addresses are looked up one byte back so a call
+/// at the very end of a function is still put in that function
+pub fn print_from(fp: usize) {
+    let mut depth = 0;
+    walk(fp, |ra| {
+        match lookup(ra - 1) {
+            Some(symbol) => println!(
+                "  #{:<2} {:#018x} {}+{:#x}",
+                depth,
+                ra,
+                symbol.name,
+                ra - symbol.addr
+            ),
+            None => println!("  #{:<2} {:#018x} ?", depth, ra),
+        }
+        depth += 1;
+    });
+    if depth == 0 {
+        println!("  no frames found from fp {:#x}", fp);
+    } else if table().len() <= HEADER_SIZE {
+        println!("  (no symbols, build with build.sh to embed them)");
+    }
+}
+
+/// print the backtrace from the calling function on
+#[inline(never)]
+pub fn print() {
+    let fp: usize;
+    unsafe { asm!("mv {0}, s0", out(reg) fp) };
+    println!("backtrace:");
+    print_from(fp);
+}
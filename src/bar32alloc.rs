@@ -1,8 +1,52 @@
+use alloc::vec::Vec;
+use core::fmt;
 use fdt::node::FdtNode;
 use log::*;
 
-const fn align_up(value: u32, alignment: u32) -> u32 {
-    ((value - 1) | (alignment - 1)) + 1
+/// Size in bytes of one entry of `ranges`: 3 cells of PCI address, 2 of CPU address, 2 of size.
+const RANGE_SIZE: usize = 28;
+
+/// Rounds `value` up to a multiple of the power-of-2 `alignment`, `None` if that overflows.
+pub fn align_up(value: u64, alignment: u64) -> Option<u64> {
+    value
+        .checked_add(alignment - 1)
+        .map(|value| value & !(alignment - 1))
+}
+
+/// Why the PCI address allocators couldn't be set up or couldn't place a BAR.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciAllocError {
+    /// The PCI node has no `ranges` property.
+    MissingRanges,
+    /// The `ranges` property isn't a whole number of entries.
+    MalformedRanges { len: usize },
+    /// A range has a space code outside 0..=3.
+    UnknownRangeType(u8),
+    /// There is no window of the kind asked for.
+    NoWindow,
+    /// BAR sizes are powers of 2, this isn't.
+    BadSize(u64),
+    /// The window is too full for a BAR of this size.
+    Exhausted { size: u64 },
+}
+
+impl fmt::Display for PciAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingRanges => write!(f, "PCI node missing ranges property"),
+            Self::MalformedRanges { len } => write!(
+                f,
+                "PCI ranges property is {} bytes, not a multiple of {}",
+                len, RANGE_SIZE
+            ),
+            Self::UnknownRangeType(value) => write!(f, "invalid PCI range type {}", value),
+            Self::NoWindow => write!(f, "no suitable PCI address window"),
+            Self::BadSize(size) => write!(f, "BAR size {:#x} is not a power of 2", size),
+            Self::Exhausted { size } => {
+                write!(f, "no room left for a {:#x} byte BAR", size)
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -13,77 +57,194 @@ pub enum PciRangeType {
     Memory64,
 }
 
-impl From<u8> for PciRangeType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for PciRangeType {
+    type Error = PciAllocError;
+
+    fn try_from(value: u8) -> Result<Self, PciAllocError> {
         match value {
-            0 => Self::ConfigurationSpace,
-            1 => Self::IoSpace,
-            2 => Self::Memory32,
-            3 => Self::Memory64,
-            _ => panic!("Tried to convert invalid range type {}", value),
+            0 => Ok(Self::ConfigurationSpace),
+            1 => Ok(Self::IoSpace),
+            2 => Ok(Self::Memory32),
+            3 => Ok(Self::Memory64),
+            _ => Err(PciAllocError::UnknownRangeType(value)),
+        }
+    }
+}
+
+/// One entry of a PCI host bridge's `ranges` property.
+#[derive(Copy, Clone, Debug)]
+pub struct PciRange {
+    pub range_type: PciRangeType,
+    pub prefetchable: bool,
+    pub bus_address: u64,
+    pub cpu_physical: u64,
+    pub size: u64,
+}
+
+impl PciRange {
+    /// The end of the range in bus addresses, saturating instead of overflowing.
+    pub fn bus_end(&self) -> u64 {
+        self.bus_address.saturating_add(self.size)
+    }
+}
+
+/// Parses the ranges property of the given PCI node.
+pub fn pci_ranges(pci_node: &FdtNode) -> Result<Vec<PciRange>, PciAllocError> {
+    let ranges = pci_node
+        .property("ranges")
+        .ok_or(PciAllocError::MissingRanges)?;
+    if ranges.value.len() % RANGE_SIZE != 0 {
+        return Err(PciAllocError::MalformedRanges {
+            len: ranges.value.len(),
+        });
+    }
+    ranges
+        .value
+        .chunks_exact(RANGE_SIZE)
+        .map(|range| {
+            Ok(PciRange {
+                range_type: PciRangeType::try_from(range[0] & 0x3)?,
+                prefetchable: range[0] & 0x80 != 0,
+                bus_address: u64::from_be_bytes(range[4..12].try_into().unwrap()),
+                cpu_physical: u64::from_be_bytes(range[12..20].try_into().unwrap()),
+                size: u64::from_be_bytes(range[20..28].try_into().unwrap()),
+            })
+        })
+        .collect()
+}
+
+/// A window of bus addresses handed out bottom up, and where the CPU sees it.
+#[derive(Copy, Clone, Debug)]
+pub struct BusWindow {
+    start: u64,
+    /// The next free bus address.
+    next: u64,
+    end: u64,
+    /// What to add to a bus address to get the CPU physical address, wrapping.
+    cpu_offset: u64,
+}
+
+impl BusWindow {
+    pub fn new(bus_start: u64, bus_end: u64, cpu_physical: u64) -> Self {
+        Self {
+            start: bus_start,
+            next: bus_start,
+            end: bus_end,
+            cpu_offset: cpu_physical.wrapping_sub(bus_start),
         }
     }
+
+    pub fn for_range(range: &PciRange) -> Self {
+        Self::new(range.bus_address, range.bus_end(), range.cpu_physical)
+    }
+
+    /// Takes a size aligned block of the power-of-2 `size` bytes, as a bus address.
+    pub fn allocate(&mut self, size: u64) -> Result<u64, PciAllocError> {
+        if !size.is_power_of_two() {
+            return Err(PciAllocError::BadSize(size));
+        }
+        let allocated_address = align_up(self.next, size)
+            .filter(|address| {
+                address
+                    .checked_add(size)
+                    .map_or(false, |end| end <= self.end)
+            })
+            .ok_or(PciAllocError::Exhausted { size })?;
+        self.next = allocated_address + size;
+        Ok(allocated_address)
+    }
+
+    /// The next free bus address.
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+
+    /// Skips ahead to a multiple of `granularity`, so what's allocated from here on can be
+    /// covered by a bridge window without taking in anything before it.
+    pub fn align_next(&mut self, granularity: u64) {
+        self.next = align_up(self.next, granularity).map_or(self.end, |next| next.min(self.end));
+    }
+
+    pub fn contains(&self, bus_address: u64) -> bool {
+        (self.start..self.end).contains(&bus_address)
+    }
+
+    /// The CPU physical address `bus_address` is reached at.
+    pub fn cpu_address(&self, bus_address: u64) -> u64 {
+        bus_address.wrapping_add(self.cpu_offset)
+    }
 }
 
 /// Allocates 32-bit memory addresses for PCI BARs.
 pub struct PciMemory32Allocator {
-    start: u32,
-    end: u32,
+    window: BusWindow,
 }
 
 impl PciMemory32Allocator {
     /// Creates a new allocator based on the ranges property of the given PCI node.
-    pub fn for_pci_ranges(pci_node: &FdtNode) -> Self {
-        let ranges = pci_node
-            .property("ranges")
-            .expect("PCI node missing ranges property.");
-        let mut memory_32_address = 0;
-        let mut memory_32_size = 0;
-        for i in 0..ranges.value.len() / 28 {
-            let range = &ranges.value[i * 28..(i + 1) * 28];
-            let prefetchable = range[0] & 0x80 != 0;
-            let range_type = PciRangeType::from(range[0] & 0x3);
-            let bus_address = u64::from_be_bytes(range[4..12].try_into().unwrap());
-            let cpu_physical = u64::from_be_bytes(range[12..20].try_into().unwrap());
-            let size = u64::from_be_bytes(range[20..28].try_into().unwrap());
+    pub fn for_pci_ranges(pci_node: &FdtNode) -> Result<Self, PciAllocError> {
+        let mut memory_32: Option<PciRange> = None;
+        for range in pci_ranges(pci_node)? {
             info!(
                 "range: {:?} {}prefetchable bus address: {:#018x} host physical address: {:#018x} size: {:#018x}",
-                range_type,
-                if prefetchable { "" } else { "non-" },
-                bus_address,
-                cpu_physical,
-                size,
+                range.range_type,
+                if range.prefetchable { "" } else { "non-" },
+                range.bus_address,
+                range.cpu_physical,
+                range.size,
             );
             // Use the largest range within the 32-bit address space for 32-bit memory, even if it
             // is marked as a 64-bit range. This is necessary because crosvm doesn't currently
-            // provide any 32-bit ranges.
-            if !prefetchable
-                && matches!(range_type, PciRangeType::Memory32 | PciRangeType::Memory64)
-                && size > memory_32_size.into()
-                && bus_address + size < u32::MAX.into()
+            // provide any 32-bit ranges. Only the bus side has to fit in 32 bits.
+            if !range.prefetchable
+                && matches!(
+                    range.range_type,
+                    PciRangeType::Memory32 | PciRangeType::Memory64
+                )
+                && range.size > memory_32.map_or(0, |memory_32| memory_32.size)
+                && range.bus_end() < u32::MAX.into()
             {
-                assert_eq!(bus_address, cpu_physical);
-                memory_32_address = u32::try_from(cpu_physical).unwrap();
-                memory_32_size = u32::try_from(size).unwrap();
+                memory_32 = Some(range);
             }
         }
-        if memory_32_size == 0 {
-            panic!("No 32-bit PCI memory region found.");
-        }
-        Self {
-            start: memory_32_address,
-            end: memory_32_address + memory_32_size,
+        let memory_32 = memory_32.ok_or(PciAllocError::NoWindow)?;
+        if memory_32.bus_address != memory_32.cpu_physical {
+            info!(
+                "32-bit PCI memory at bus {:#x} is at {:#x} for the CPU",
+                memory_32.bus_address, memory_32.cpu_physical
+            );
         }
+        Ok(Self {
+            window: BusWindow::for_range(&memory_32),
+        })
     }
 
     /// Allocates a 32-bit memory address region for a PCI BAR of the given power-of-2 size.
     ///
-    /// It will have alignment matching the size. The size must be a power of 2.
-    pub fn allocate_memory_32(&mut self, size: u32) -> u32 {
-        assert!(size.is_power_of_two());
-        let allocated_address = align_up(self.start, size);
-        assert!(allocated_address + size <= self.end);
-        self.start = allocated_address + size;
-        allocated_address
+    /// It will have alignment matching the size. The address is a bus address, for the BAR,
+    /// `cpu_address` translates it for the CPU.
+    pub fn allocate_memory_32(&mut self, size: u32) -> Result<u32, PciAllocError> {
+        // the window ends below 4 GiB, so anything in it fits
+        self.window
+            .allocate(size.into())
+            .map(|address| address as u32)
+    }
+
+    /// The CPU physical address of the bus address `bus_address`.
+    pub fn cpu_address(&self, bus_address: u32) -> u64 {
+        self.window.cpu_address(bus_address.into())
+    }
+
+    /// Whether the bus address `bus_address` is in the window.
+    pub fn contains(&self, bus_address: u32) -> bool {
+        self.window.contains(bus_address.into())
+    }
+
+    pub fn window(&self) -> &BusWindow {
+        &self.window
+    }
+
+    pub fn window_mut(&mut self) -> &mut BusWindow {
+        &mut self.window
     }
 }
@@ -0,0 +1,124 @@
+use crate::bar32alloc::{pci_ranges, BusWindow, PciAllocError, PciRangeType};
+use alloc::vec::Vec;
+use fdt::node::FdtNode;
+use log::*;
+
+/// One 64-bit memory window of the host bridge.
+#[derive(Copy, Clone, Debug)]
+struct Window {
+    addresses: BusWindow,
+    prefetchable: bool,
+}
+
+/// Allocates 64-bit memory addresses for PCI BARs, the companion to `PciMemory32Allocator`.
+pub struct PciMemory64Allocator {
+    windows: Vec<Window>,
+}
+
+impl PciMemory64Allocator {
+    /// Creates a new allocator from the `Memory64` ranges of the given PCI node that reach above
+    /// 4 GiB, prefetchable or not. Ranges entirely below 4 GiB are left to the 32-bit allocator.
+    /// Fails with `NoWindow` if there are no such ranges.
+    pub fn for_pci_ranges(pci_node: &FdtNode) -> Result<Self, PciAllocError> {
+        let windows: Vec<_> = pci_ranges(pci_node)?
+            .into_iter()
+            .filter(|range| {
+                range.range_type == PciRangeType::Memory64 && range.bus_end() > u32::MAX.into()
+            })
+            .map(|range| {
+                // Only the part above 4 GiB, in case the range straddles it.
+                let start = range.bus_address.max(1 << 32);
+                let cpu_start = range.cpu_physical + (start - range.bus_address);
+                debug!(
+                    "64-bit {}prefetchable window {:#x}..{:#x} at {:#x}",
+                    if range.prefetchable { "" } else { "non-" },
+                    start,
+                    range.bus_end(),
+                    cpu_start
+                );
+                Window {
+                    addresses: BusWindow::new(start, range.bus_end(), cpu_start),
+                    prefetchable: range.prefetchable,
+                }
+            })
+            .collect();
+        if windows.is_empty() {
+            return Err(PciAllocError::NoWindow);
+        }
+        Ok(Self { windows })
+    }
+
+    /// Allocates a 64-bit memory address region for a PCI BAR of the given power-of-2 size,
+    /// aligned to its size. The address is a bus address, for the BAR.
+    ///
+    /// Prefetchable BARs go in a prefetchable window if there is one, and in a non-prefetchable
+    /// one otherwise. Non-prefetchable BARs only ever go in non-prefetchable windows, since the
+    /// bridge may merge or cache accesses to the others. Fails with `NoWindow` if there is no
+    /// window of the right kind, so the caller can fall back to 32-bit space.
+    pub fn allocate_memory_64(
+        &mut self,
+        size: u64,
+        prefetchable: bool,
+    ) -> Result<u64, PciAllocError> {
+        self.allocate_memory_64_in(&mut None, size, prefetchable)
+    }
+
+    /// Like `allocate_memory_64`, but keeps to one window: the one `window` names, or if that's
+    /// `None` the first that fits, which `window` is then set to. A PCI-to-PCI bridge forwards
+    /// a single 64-bit range, so everything behind one has to come from the same window.
+    /// Fails with `NoWindow` if the named window is of the wrong kind or too full.
+    pub fn allocate_memory_64_in(
+        &mut self,
+        window: &mut Option<usize>,
+        size: u64,
+        prefetchable: bool,
+    ) -> Result<u64, PciAllocError> {
+        if let Some(index) = *window {
+            let pinned = &mut self.windows[index];
+            if !prefetchable && pinned.prefetchable {
+                return Err(PciAllocError::NoWindow);
+            }
+            return pinned
+                .addresses
+                .allocate(size)
+                .map_err(|_| PciAllocError::NoWindow);
+        }
+        let preference = if prefetchable {
+            &[true, false][..]
+        } else {
+            &[false][..]
+        };
+        let mut result = Err(PciAllocError::NoWindow);
+        for &want_prefetchable in preference {
+            for (index, candidate) in self
+                .windows
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, candidate)| candidate.prefetchable == want_prefetchable)
+            {
+                result = candidate.addresses.allocate(size);
+                if result.is_ok() {
+                    *window = Some(index);
+                    return result;
+                }
+            }
+        }
+        result
+    }
+
+    /// The CPU physical address of the bus address `bus_address`, if it's in one of the windows.
+    pub fn cpu_address(&self, bus_address: u64) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| window.addresses.contains(bus_address))
+            .map(|window| window.addresses.cpu_address(bus_address))
+    }
+
+    pub fn windows(&self) -> impl Iterator<Item = &BusWindow> {
+        self.windows.iter().map(|window| &window.addresses)
+    }
+
+    pub fn windows_mut(&mut self) -> impl Iterator<Item = &mut BusWindow> {
+        self.windows.iter_mut().map(|window| &mut window.addresses)
+    }
+}
@@ -0,0 +1,59 @@
+use crate::bar32alloc::{pci_ranges, BusWindow, PciAllocError, PciRangeType};
+use fdt::node::FdtNode;
+use log::*;
+
+/// Ports below this are left alone if the window is big enough: port 0 reads as unassigned, and
+/// the low ports belong to legacy ISA devices on PC style platforms.
+const FIRST_PORT: u64 = 0x1000;
+
+/// Allocates I/O port ranges for PCI I/O BARs out of the host bridge's `IoSpace` window.
+///
+/// There are no I/O instructions on RISC-V, the bridge maps the port space into memory at
+/// `cpu_physical`, so port `p` is accessed at `cpu_address(p)`.
+pub struct PciIoAllocator {
+    window: BusWindow,
+}
+
+impl PciIoAllocator {
+    /// Creates a new allocator based on the `IoSpace` range of the given PCI node, failing with
+    /// `NoWindow` if it doesn't have one.
+    pub fn for_pci_ranges(pci_node: &FdtNode) -> Result<Self, PciAllocError> {
+        let range = pci_ranges(pci_node)?
+            .into_iter()
+            .filter(|range| range.range_type == PciRangeType::IoSpace)
+            .max_by_key(|range| range.size)
+            .ok_or(PciAllocError::NoWindow)?;
+        // I/O BARs are 32 bits wide
+        let end = range.bus_end().min(u32::MAX.into());
+        let mut window = BusWindow::new(range.bus_address, end, range.cpu_physical);
+        if range.bus_address < FIRST_PORT && end > FIRST_PORT * 2 {
+            window = BusWindow::new(FIRST_PORT, end, window.cpu_address(FIRST_PORT));
+        }
+        debug!(
+            "I/O ports {:#x}..{:#x} at {:#x}",
+            range.bus_address, end, range.cpu_physical
+        );
+        Ok(Self { window })
+    }
+
+    /// Allocates a port range for a PCI I/O BAR of the given power-of-2 size.
+    ///
+    /// It will have alignment matching the size.
+    pub fn allocate_io(&mut self, size: u32) -> Result<u32, PciAllocError> {
+        let mut port = self.window.allocate(size.into())?;
+        // never port 0, even in a window that starts there
+        if port == 0 {
+            port = self.window.allocate(size.into())?;
+        }
+        Ok(port as u32)
+    }
+
+    /// The memory address port `port` is reached through.
+    pub fn cpu_address(&self, port: u32) -> u64 {
+        self.window.cpu_address(port.into())
+    }
+
+    pub fn window_mut(&mut self) -> &mut BusWindow {
+        &mut self.window
+    }
+}
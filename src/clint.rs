@@ -0,0 +1,55 @@
+// clint.rs
+// the core local interruptor, timer and software interrupts in M-mode. in S-mode the firmware owns it
+#![allow(dead_code)]
+use fdt::Fdt;
+use spin::Once;
+
+/// per-hart software interrupt pending bits, one u32 each
+const MSIP: usize = 0x0;
+/// per-hart timer comparators, one u64 each
+const MTIMECMP: usize = 0x4000;
+/// the free running counter
+const MTIME: usize = 0xbff8;
+
+static BASE: Once<usize> = Once::new();
+
+/// find the CLINT in the device tree, false if there isn't one
+pub fn init(fdt: &Fdt) -> bool {
+    let base = fdt
+        .find_compatible(&["riscv,clint0", "sifive,clint0"])
+        .and_then(|clint| clint.reg())
+        .and_then(|mut reg| reg.next())
+        .map(|reg| reg.starting_address as usize);
+    match base {
+        Some(base) => {
+            BASE.call_once(|| base);
+            true
+        }
+        None => false,
+    }
+}
+
+/// base address of the CLINT, `None` until `init` found it
+pub fn base() -> Option<usize> {
+    BASE.get().copied()
+}
+
+pub fn mtime() -> Option<u64> {
+    base().map(|base| unsafe { ((base + MTIME) as *const u64).read_volatile() })
+}
+
+/// program `hart`'s comparator, writing it also clears a pending timer interrupt
+pub fn set_mtimecmp(hart: usize, deadline: u64) {
+    if let Some(base) = base() {
+        unsafe { ((base + MTIMECMP + 8 * hart) as *mut u64).write_volatile(deadline) };
+    }
+}
+
+/// raise (or clear) the software interrupt of `hart`
+pub fn set_msip(hart: usize, pending: bool) -> bool {
+    let Some(base) = base() else {
+        return false;
+    };
+    unsafe { ((base + MSIP + 4 * hart) as *mut u32).write_volatile(pending as u32) };
+    true
+}
@@ -0,0 +1,219 @@
+// cmdline.rs
+// the kernel command line from `/chosen/bootargs`: `key=value` options and bare flags,
+// separated by spaces, values can be double quoted to hold spaces
+#![allow(dead_code)]
+use crate::{sync::Once, term::Wrap};
+use alloc::{string::String, vec::Vec};
+use core::str::FromStr;
+use fdt::Fdt;
+use log::*;
+
+pub struct Arg {
+    pub key: String,
+    /// `None` for a flag
+    pub value: Option<String>,
+}
+
+static ARGS: Once<Vec<Arg>> = Once::new();
+
+/// split `bootargs` into arguments. quotes only group, they don't end up in the value
+pub fn parse(bootargs: &str) -> Vec<Arg> {
+    let mut args = Vec::new();
+    let mut chars = bootargs.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return args;
+        }
+        let mut word = String::new();
+        let mut quoted = false;
+        let mut split = None;
+        while let Some(c) = chars.next_if(|&c| quoted || !c.is_whitespace()) {
+            match c {
+                '"' => quoted = !quoted,
+                '=' if !quoted && split.is_none() => split = Some(word.len()),
+                _ => word.push(c),
+            }
+        }
+        args.push(match split {
+            Some(at) => Arg {
+                value: Some(word.split_off(at)),
+                key: word,
+            },
+            None => Arg {
+                key: word,
+                value: None,
+            },
+        });
+    }
+}
+
+/// read `bootargs` from the device tree, a missing property is an empty command line
+pub fn init(fdt: &Fdt) {
+    ARGS.call_once(|| {
+        let bootargs = fdt.chosen().bootargs().unwrap_or("");
+        info!("cmdline: {}", bootargs);
+        parse(bootargs)
+    });
+}
+
+fn args() -> &'static [Arg] {
+    ARGS.get().map_or(&[], |args| args.as_slice())
+}
+
+/// `get` on any list of arguments
+pub fn value_in<'a>(args: &'a [Arg], key: &str) -> Option<&'a str> {
+    args.iter()
+        .rev()
+        .find(|arg| arg.key == key)
+        .and_then(|arg| arg.value.as_deref())
+}
+
+/// the value of `key=value`, the last one wins if it's given more than once. `None` for flags
+pub fn get(key: &str) -> Option<&'static str> {
+    value_in(args(), key)
+}
+
+/// `key` is there at all, as a flag or with a value
+pub fn has(key: &str) -> bool {
+    args().iter().any(|arg| arg.key == key)
+}
+
+/// a bare `key` is on, `key=on/off` and friends set it explicitly
+pub fn flag(key: &str) -> bool {
+    match args().iter().rev().find(|arg| arg.key == key) {
+        Some(Arg { value: None, .. }) => true,
+        Some(Arg {
+            value: Some(value), ..
+        }) => parse_bool(value).unwrap_or_else(|| {
+            warn!(
+                "cmdline: {}={} isn't a yes or no, taking it as no",
+                key, value
+            );
+            false
+        }),
+        None => false,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "y" | "yes" | "on" | "true" => Some(true),
+        "0" | "n" | "no" | "off" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// the value of `key` parsed as `T`, values that don't parse are reported and ignored
+pub fn get_parsed<T: FromStr>(key: &str) -> Option<T> {
+    let value = get(key)?;
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        warn!(
+            "cmdline: ignoring {}={}, can't make sense of it",
+            key, value
+        );
+    }
+    parsed
+}
+
+/// like `get_parsed`, but `0x` in front means hex
+pub fn get_usize(key: &str) -> Option<usize> {
+    let value = get(key)?;
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    if parsed.is_none() {
+        warn!("cmdline: ignoring {}={}, not a number", key, value);
+    }
+    parsed
+}
+
+/// where the console goes, `console=uart` (the default) or `console=virtio`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Console {
+    Uart,
+    Virtio,
+}
+
+impl FromStr for Console {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "uart" | "serial" => Ok(Self::Uart),
+            "virtio" | "hvc" => Ok(Self::Virtio),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn console() -> Console {
+    get_parsed("console").unwrap_or(Console::Uart)
+}
+
+/// `log=info,virtio_hal=debug`, the spec `logger::configure` takes
+pub fn log() -> Option<&'static str> {
+    get("log")
+}
+
+/// `root=virtio_mmio@10008000` or just `root=10008000`, the block device to use
+pub fn root() -> Option<&'static str> {
+    get("root")
+}
+
+/// `root` names the device tree node `node_name`, anything goes without a `root`
+pub fn is_root(node_name: &str) -> bool {
+    root().map_or(true, |root| {
+        root == node_name
+            || node_name
+                .split_once('@')
+                .map_or(false, |(_, unit)| unit == root)
+    })
+}
+
+/// what to do once a panic has been reported, `panic=halt` (the default), `poweroff` or `reboot`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PanicAction {
+    Halt,
+    PowerOff,
+    Reboot,
+}
+
+impl FromStr for PanicAction {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "halt" => Ok(Self::Halt),
+            "poweroff" | "shutdown" => Ok(Self::PowerOff),
+            "reboot" => Ok(Self::Reboot),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn panic_action() -> PanicAction {
+    get_parsed("panic").unwrap_or(PanicAction::Halt)
+}
+
+/// `selftest` runs the kernel self-tests before anything else
+pub fn selftest() -> bool {
+    flag("selftest")
+}
+
+/// `lspci` prints the `lspci -vv` view of every PCI function instead of one line each
+pub fn lspci() -> bool {
+    flag("lspci")
+}
+
+/// `term.wrap=off`, `width` or a column count, how the console breaks long lines
+pub fn term_wrap() -> Option<Wrap> {
+    get_parsed("term.wrap")
+}
+
+/// `term.colors=on/off`, whether styled output reaches the console
+pub fn term_colors() -> Option<bool> {
+    has("term.colors").then(|| flag("term.colors"))
+}
@@ -0,0 +1,205 @@
+// csr.rs
+// helpers for the control and status registers that differ between M-mode and S-mode
+#![allow(dead_code)]
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// read a csr by name
+#[macro_export]
+macro_rules! csr_read {
+    ($csr:literal) => {{
+        let value: usize;
+        #[allow(unused_unsafe)]
+        unsafe {
+            core::arch::asm!(concat!("csrr {0}, ", $csr), out(reg) value)
+        };
+        value
+    }};
+}
+
+/// write a csr by name
+#[macro_export]
+macro_rules! csr_write {
+    ($csr:literal, $value:expr) => {{
+        let value: usize = $value;
+        #[allow(unused_unsafe)]
+        unsafe {
+            core::arch::asm!(concat!("csrw ", $csr, ", {0}"), in(reg) value)
+        };
+    }};
+}
+
+/// set bits in a csr by name
+#[macro_export]
+macro_rules! csr_set {
+    ($csr:literal, $bits:expr) => {{
+        let bits: usize = $bits;
+        #[allow(unused_unsafe)]
+        unsafe {
+            core::arch::asm!(concat!("csrs ", $csr, ", {0}"), in(reg) bits)
+        };
+    }};
+}
+
+/// clear bits in a csr by name
+#[macro_export]
+macro_rules! csr_clear {
+    ($csr:literal, $bits:expr) => {{
+        let bits: usize = $bits;
+        #[allow(unused_unsafe)]
+        unsafe {
+            core::arch::asm!(concat!("csrc ", $csr, ", {0}"), in(reg) bits)
+        };
+    }};
+}
+
+/// the privilege level the kernel is running at
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// booted with `-bios none`, we own the machine
+    Machine,
+    /// booted by a firmware (OpenSBI), we only get the supervisor registers
+    Supervisor,
+}
+
+const MODE_UNKNOWN: u8 = 0;
+const MODE_MACHINE: u8 = 1;
+const MODE_SUPERVISOR: u8 = 2;
+static MODE: AtomicU8 = AtomicU8::new(MODE_UNKNOWN);
+
+/// status register bit that globally enables interrupts (MIE/SIE)
+pub const STATUS_IE_MACHINE: usize = 1 << 3;
+pub const STATUS_IE_SUPERVISOR: usize = 1 << 1;
+
+/// interrupt enable bits for the `mie`/`sie` registers
+pub const IE_SOFTWARE_MACHINE: usize = 1 << 3;
+pub const IE_SOFTWARE_SUPERVISOR: usize = 1 << 1;
+pub const IE_TIMER_MACHINE: usize = 1 << 7;
+pub const IE_TIMER_SUPERVISOR: usize = 1 << 5;
+pub const IE_EXTERNAL_MACHINE: usize = 1 << 11;
+pub const IE_EXTERNAL_SUPERVISOR: usize = 1 << 9;
+
+/// figure out if we are in M-mode or S-mode by trying to read `mhartid`.
+/// in S-mode the read traps and the firmware redirects it to `stvec`, which we point just past the probe.
+/// must be called before any other trap vector is installed
+pub fn detect_mode() -> Mode {
+    let machine: usize;
+    unsafe {
+        asm!(
+            "csrr {old}, stvec",
+            "la {tmp}, 1f",
+            "csrw stvec, {tmp}",
+            "li {machine}, 0",
+            "csrr {tmp}, mhartid",
+            "li {machine}, 1",
+            ".align 2",
+            "1:",
+            "csrw stvec, {old}",
+            old = out(reg) _,
+            tmp = out(reg) _,
+            machine = out(reg) machine,
+        );
+    }
+    let mode = if machine == 1 {
+        Mode::Machine
+    } else {
+        Mode::Supervisor
+    };
+    MODE.store(
+        match mode {
+            Mode::Machine => MODE_MACHINE,
+            Mode::Supervisor => MODE_SUPERVISOR,
+        },
+        Ordering::Relaxed,
+    );
+    mode
+}
+
+/// the mode found by `detect_mode`
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        MODE_MACHINE => Mode::Machine,
+        MODE_SUPERVISOR => Mode::Supervisor,
+        _ => detect_mode(),
+    }
+}
+
+/// set the trap vector (`mtvec`/`stvec`) in direct mode
+pub fn set_trap_vector(addr: usize) {
+    match mode() {
+        Mode::Machine => csr_write!("mtvec", addr),
+        Mode::Supervisor => csr_write!("stvec", addr),
+    }
+}
+
+/// set `mscratch`/`sscratch`, the trap vector finds the trap stack there
+pub fn set_trap_scratch(value: usize) {
+    match mode() {
+        Mode::Machine => csr_write!("mscratch", value),
+        Mode::Supervisor => csr_write!("sscratch", value),
+    }
+}
+
+/// enable the given bits in `mie`/`sie`
+pub fn enable_interrupt_sources(bits: usize) {
+    match mode() {
+        Mode::Machine => csr_set!("mie", bits),
+        Mode::Supervisor => csr_set!("sie", bits),
+    }
+}
+
+/// disable the given bits in `mie`/`sie`
+pub fn disable_interrupt_sources(bits: usize) {
+    match mode() {
+        Mode::Machine => csr_clear!("mie", bits),
+        Mode::Supervisor => csr_clear!("sie", bits),
+    }
+}
+
+fn status_ie() -> usize {
+    match mode() {
+        Mode::Machine => STATUS_IE_MACHINE,
+        Mode::Supervisor => STATUS_IE_SUPERVISOR,
+    }
+}
+
+/// are interrupts globally enabled on this hart
+pub fn interrupts_enabled() -> bool {
+    let status = match mode() {
+        Mode::Machine => csr_read!("mstatus"),
+        Mode::Supervisor => csr_read!("sstatus"),
+    };
+    status & status_ie() != 0
+}
+
+/// globally enable interrupts on this hart
+pub fn enable_interrupts() {
+    match mode() {
+        Mode::Machine => csr_set!("mstatus", STATUS_IE_MACHINE),
+        Mode::Supervisor => csr_set!("sstatus", STATUS_IE_SUPERVISOR),
+    }
+}
+
+/// globally disable interrupts on this hart, returning whether they were enabled before
+pub fn disable_interrupts() -> bool {
+    let was_enabled = interrupts_enabled();
+    match mode() {
+        Mode::Machine => csr_clear!("mstatus", STATUS_IE_MACHINE),
+        Mode::Supervisor => csr_clear!("sstatus", STATUS_IE_SUPERVISOR),
+    }
+    was_enabled
+}
+
+/// re-enable interrupts if `disable_interrupts` said they were on
+pub fn restore_interrupts(was_enabled: bool) {
+    if was_enabled {
+        enable_interrupts()
+    }
+}
+
+/// wait for an interrupt
+pub fn wfi() {
+    unsafe { asm!("wfi") }
+}
@@ -0,0 +1,300 @@
+// executor.rs
+// a small executor for driver code written as `async fn`. futures wait on PLIC interrupt ids and
+// timer deadlines, and the hart sleeps in `wfi` while nothing is ready
+#![allow(dead_code)]
+use crate::{
+    csr,
+    layout::MAX_HARTS,
+    smp::{self, IpiMessage},
+    sync::IrqSafeSpinlock,
+    time::{self, Instant},
+};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
+use core::{
+    cell::RefCell,
+    future::{poll_fn, Future},
+    pin::{pin, Pin},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+/// wakers waiting for an interrupt id, all of them are woken the next time it fires
+static IRQ_WAKERS: IrqSafeSpinlock<BTreeMap<u32, Vec<Waker>>> =
+    IrqSafeSpinlock::new("irq wakers", BTreeMap::new());
+
+// only used to fill the array below, each element is its own static
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TIMERS: IrqSafeSpinlock<Vec<(u64, Waker)>> = IrqSafeSpinlock::new("timers", Vec::new());
+/// wakers waiting for a tick count, per hart since each hart has its own timer interrupt
+static TIMERS: [IrqSafeSpinlock<Vec<(u64, Waker)>>; MAX_HARTS] = [NO_TIMERS; MAX_HARTS];
+
+/// add `waker` to `wakers` unless it would wake the same task as one already there
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|other| other.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+/// called by the PLIC once it has run the handlers for `id`
+pub fn wake_irq(id: u32) {
+    let wakers = IRQ_WAKERS.lock().remove(&id);
+    for waker in wakers.into_iter().flatten() {
+        waker.wake();
+    }
+}
+
+/// called from the trap handler after the timer fired, wakes whatever is due and re-arms for the rest
+pub fn wake_timers() {
+    let now = time::ticks();
+    let mut due = Vec::new();
+    let mut timers = TIMERS[smp::hart_id()].lock();
+    timers.retain(|(deadline, waker)| {
+        if *deadline <= now {
+            due.push(waker.clone());
+        }
+        *deadline > now
+    });
+    if let Some(next) = timers.iter().map(|(deadline, _)| *deadline).min() {
+        time::arm(Instant::from_ticks(next));
+    }
+    drop(timers);
+    for waker in due {
+        waker.wake();
+    }
+}
+
+/// resolves once `poll` returns something. `poll` is checked again every time interrupt `irq` fires,
+/// with no interrupt to wait on it is checked every time the executor comes around
+pub fn wait_irq<T>(
+    irq: Option<u32>,
+    mut poll: impl FnMut() -> Option<T>,
+) -> impl Future<Output = T> {
+    poll_fn(move |cx| {
+        let Some(irq) = irq else {
+            return match poll() {
+                Some(value) => Poll::Ready(value),
+                None => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            };
+        };
+        // register before checking, so an interrupt between the check and returning still wakes us
+        register(IRQ_WAKERS.lock().entry(irq).or_default(), cx.waker());
+        match poll() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    })
+}
+
+/// a future that resolves at a point on the monotonic clock
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let mut timers = TIMERS[smp::hart_id()].lock();
+        let waiting = timers.iter().any(|(deadline, waker)| {
+            *deadline == self.deadline.ticks() && waker.will_wake(cx.waker())
+        });
+        if !waiting {
+            timers.push((self.deadline.ticks(), cx.waker().clone()));
+        }
+        // still holding the lock, so the timer can't fire and re-arm in between
+        time::arm(self.deadline);
+        Poll::Pending
+    }
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline }
+}
+
+/// run `future` for at most `duration`, `None` if it didn't finish in time
+pub async fn with_timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut sleep = sleep(duration);
+    poll_fn(|cx| {
+        if let Poll::Ready(value) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(value));
+        }
+        Pin::new(&mut sleep).poll(cx).map(|()| None)
+    })
+    .await
+}
+
+/// the ids of tasks that have been woken, shared between an executor and its wakers
+struct ReadyQueue {
+    hart: usize,
+    ready: IrqSafeSpinlock<VecDeque<usize>>,
+}
+
+impl ReadyQueue {
+    fn push(&self, id: usize) {
+        // wakers run from interrupt handlers too, which the lock keeping interrupts off allows for
+        let mut ready = self.ready.lock();
+        if !ready.contains(&id) {
+            ready.push_back(id);
+        }
+        drop(ready);
+        if self.hart != smp::hart_id() {
+            smp::send_ipi(self.hart, IpiMessage::Wake);
+        }
+    }
+
+    fn pop(&self) -> Option<usize> {
+        self.ready.lock().pop_front()
+    }
+
+    /// sleep until something is woken. the check happens with interrupts masked so a wake up
+    /// can't slip in between it and the `wfi`, same as `time::wait_until`
+    fn wait(&self) {
+        let was_enabled = csr::disable_interrupts();
+        if was_enabled && self.ready.lock().is_empty() {
+            csr::wfi();
+        }
+        csr::restore_interrupts(was_enabled);
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    queue: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.queue.push(self.id);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.push(self.id);
+    }
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Waker,
+}
+
+/// id of the future passed to `block_on`, spawned tasks count up from 1
+const MAIN_TASK: usize = 0;
+
+/// runs futures on the hart it was created on. futures don't need to be `Send`,
+/// but everything they wait on can be woken from any hart
+pub struct Executor {
+    queue: Arc<ReadyQueue>,
+    tasks: RefCell<BTreeMap<usize, Task>>,
+    next_id: RefCell<usize>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            queue: Arc::new(ReadyQueue {
+                hart: smp::hart_id(),
+                ready: IrqSafeSpinlock::new("ready queue", VecDeque::new()),
+            }),
+            tasks: RefCell::new(BTreeMap::new()),
+            next_id: RefCell::new(MAIN_TASK + 1),
+        }
+    }
+
+    fn waker(&self, id: usize) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            id,
+            queue: self.queue.clone(),
+        }))
+    }
+
+    /// run `future` alongside the others, it gets polled once `block_on` or `run` is running
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        let id = {
+            let mut next_id = self.next_id.borrow_mut();
+            *next_id += 1;
+            *next_id - 1
+        };
+        let task = Task {
+            future: Box::pin(future),
+            waker: self.waker(id),
+        };
+        self.tasks.borrow_mut().insert(id, task);
+        self.queue.push(id);
+    }
+
+    /// number of spawned tasks that haven't finished
+    pub fn pending(&self) -> usize {
+        self.tasks.borrow().len()
+    }
+
+    fn poll_task(&self, id: usize) {
+        // taken out while it runs so it can spawn more tasks
+        let Some(mut task) = self.tasks.borrow_mut().remove(&id) else {
+            // woken after it finished
+            return;
+        };
+        let mut cx = Context::from_waker(&task.waker);
+        if task.future.as_mut().poll(&mut cx).is_pending() {
+            self.tasks.borrow_mut().insert(id, task);
+        }
+    }
+
+    /// run the spawned tasks and `future` until `future` finishes, the others are left where they are
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = self.waker(MAIN_TASK);
+        let mut cx = Context::from_waker(&waker);
+        self.queue.push(MAIN_TASK);
+        loop {
+            while let Some(id) = self.queue.pop() {
+                if id != MAIN_TASK {
+                    self.poll_task(id);
+                } else if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                    return value;
+                }
+            }
+            self.queue.wait();
+        }
+    }
+
+    /// run the spawned tasks until all of them are done
+    pub fn run(&self) {
+        while self.pending() > 0 {
+            while let Some(id) = self.queue.pop() {
+                self.poll_task(id);
+            }
+            if self.pending() > 0 {
+                self.queue.wait();
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// run `future` to completion on a fresh executor
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    Executor::new().block_on(future)
+}
@@ -0,0 +1,263 @@
+// heap.rs
+// the global allocator, talc arenas that start in the static .heap region and grow with frames from pmm
+#![allow(dead_code)]
+use crate::{
+    pmm::{self, PAGE_SIZE},
+    sync::IrqSafeSpinlock,
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    ptr::{self, NonNull},
+};
+use talc::{ErrOnOom, Span, Talc};
+
+/// size of the arena in the static .heap region, enough to get through boot until pmm is up
+pub const INITIAL_HEAP_SIZE: usize = 0x40000;
+/// talc only manages one contiguous arena, so every block claimed from pmm becomes its own
+pub const MAX_ARENAS: usize = 32;
+/// room for talc's bins and chunk tags when sizing a new arena
+const ARENA_OVERHEAD: usize = 0x1000;
+
+#[link_section = ".heap"] //script.ld gives the heap its own guarded region
+static mut INITIAL_ARENA: [u8; INITIAL_HEAP_SIZE] = [0; INITIAL_HEAP_SIZE];
+
+struct Heap {
+    arenas: [Option<Talc<ErrOnOom>>; MAX_ARENAS],
+    /// bytes of RAM the arenas cover
+    claimed: usize,
+    /// bytes handed out, as requested by the callers
+    in_use: usize,
+    peak: usize,
+    /// allocations that failed even after trying to grow
+    failures: usize,
+    /// allocations that failed because no single pmm block could hold them, see `grow`
+    oversized: usize,
+}
+
+// the arenas are raw pointers into memory only the heap touches, and it is only used behind the lock
+unsafe impl Send for Heap {}
+
+const NO_ARENA: Option<Talc<ErrOnOom>> = None;
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            arenas: [NO_ARENA; MAX_ARENAS],
+            claimed: 0,
+            in_use: 0,
+            peak: 0,
+            failures: 0,
+            oversized: 0,
+        }
+    }
+
+    /// hand `span` to a fresh arena, false if all the slots are taken
+    unsafe fn add_arena(&mut self, span: Span) -> bool {
+        let Some(slot) = self.arenas.iter_mut().find(|arena| arena.is_none()) else {
+            return false;
+        };
+        let mut talc = Talc::new(ErrOnOom);
+        talc.init(span);
+        *slot = Some(talc);
+        self.claimed += span.size();
+        true
+    }
+
+    /// the most `grow` can claim at once. an arena is a single pmm block, so nothing bigger than
+    /// this (less talc's overhead) can ever be allocated, however much RAM is free
+    const MAX_ARENA_SIZE: usize = PAGE_SIZE << pmm::MAX_ORDER;
+
+    /// claim a block of frames big enough for `layout`, at least doubling the heap while we're at it
+    fn grow(&mut self, layout: Layout) -> bool {
+        let needed = layout.size() + layout.align() + ARENA_OVERHEAD;
+        if needed > Self::MAX_ARENA_SIZE {
+            return false;
+        }
+        let pages = ((needed + PAGE_SIZE - 1) / PAGE_SIZE).max(self.claimed / PAGE_SIZE);
+        let order = pmm::order_for(pages).min(pmm::MAX_ORDER);
+        if self.arenas.iter().all(Option::is_some) {
+            return false;
+        }
+        let Some(block) = pmm::alloc(order) else {
+            return false;
+        };
+        unsafe { self.add_arena(Span::from_base_size(block as *mut u8, PAGE_SIZE << order)) }
+    }
+
+    unsafe fn try_alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        // newest arenas first, they're the biggest
+        self.arenas
+            .iter_mut()
+            .rev()
+            .flatten()
+            .find_map(|talc| talc.malloc(layout).ok())
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match self.try_alloc(layout) {
+            Some(ptr) => Some(ptr),
+            None if self.grow(layout) => self.try_alloc(layout),
+            None => None,
+        };
+        let Some(ptr) = ptr else {
+            if layout.size() + layout.align() + ARENA_OVERHEAD > Self::MAX_ARENA_SIZE {
+                self.oversized += 1;
+            } else {
+                self.failures += 1;
+            }
+            return ptr::null_mut();
+        };
+        self.in_use += layout.size();
+        self.peak = self.peak.max(self.in_use);
+        ptr.as_ptr()
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let talc = self
+            .arenas
+            .iter_mut()
+            .flatten()
+            .find(|talc| talc.get_arena().contains(ptr))
+            .expect("freeing memory that isn't from the heap");
+        talc.free(NonNull::new_unchecked(ptr), layout);
+        self.in_use -= layout.size();
+    }
+
+    /// the biggest allocation that would succeed without growing. talc can't list its free
+    /// chunks, so this binary searches with real allocations, freeing each one straight away
+    unsafe fn largest_free(&mut self) -> usize {
+        let mut largest = 0;
+        for talc in self.arenas.iter_mut().flatten() {
+            let span = talc.get_allocatable_span().size();
+            if span <= largest {
+                continue;
+            }
+            // only looking for something bigger than what an earlier arena had. fits always fits
+            // (or is that earlier size), too_big never does
+            let (mut fits, mut too_big) = (largest, span + 1);
+            while too_big - fits > 1 {
+                let size = fits + (too_big - fits) / 2;
+                let layout = Layout::from_size_align_unchecked(size, 1);
+                match talc.malloc(layout) {
+                    Ok(ptr) => {
+                        talc.free(ptr, layout);
+                        fits = size;
+                    }
+                    Err(()) => too_big = size,
+                }
+            }
+            largest = fits;
+        }
+        largest
+    }
+}
+
+/// the `#[global_allocator]`, see `init`. interrupt handlers allocate too (wakers, run queues),
+/// which the lock keeping interrupts off takes care of
+pub struct KernelHeap(IrqSafeSpinlock<Heap>);
+
+impl KernelHeap {
+    pub const fn new() -> Self {
+        Self(IrqSafeSpinlock::new("heap", Heap::new()))
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(ptr, layout);
+    }
+}
+
+/// a snapshot of the heap for reporting
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub arenas: usize,
+    pub claimed: usize,
+    pub in_use: usize,
+    pub peak: usize,
+    pub failures: usize,
+    /// failed allocations too big for any arena, kept apart from `failures` as growing can't help
+    pub oversized: usize,
+    /// the biggest allocation that would succeed without claiming more frames. `None` when the
+    /// panic handler took the heap over in the middle of an allocation, it isn't safe to look then
+    pub largest_free: Option<usize>,
+}
+
+impl HeapStats {
+    /// how much of the claimed memory isn't in use, in percent. talc's tags and bins count
+    /// against it too, so it never quite reaches 0
+    pub fn free_percent(&self) -> usize {
+        if self.claimed == 0 {
+            return 0;
+        }
+        (self.claimed - self.in_use) * 100 / self.claimed
+    }
+
+    /// how much of the free memory is unusable for an allocation as big as all of it, in percent:
+    /// 0 when it is one chunk, close to 100 when it is scattered. since talc only manages one
+    /// arena per pmm block, a heap with several arenas never reaches 0
+    pub fn fragmentation_percent(&self) -> Option<usize> {
+        let free = self.claimed - self.in_use;
+        if free == 0 {
+            return Some(0);
+        }
+        Some(100 - self.largest_free?.min(free) * 100 / free)
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "heap: {}/{} KiB in use (peak {} KiB, {}% free, ",
+            self.in_use / 1024,
+            self.claimed / 1024,
+            self.peak / 1024,
+            self.free_percent(),
+        )?;
+        match (self.fragmentation_percent(), self.largest_free) {
+            (Some(percent), Some(largest)) => write!(
+                f,
+                "{}% fragmented, largest free {} KiB",
+                percent,
+                largest / 1024
+            )?,
+            _ => write!(f, "fragmentation unknown")?,
+        }
+        write!(
+            f,
+            ") in {} arenas, {} failed allocations, {} too big for an arena",
+            self.arenas, self.failures, self.oversized
+        )
+    }
+}
+
+/// uses `try_lock` so the panic handler can call it, even when the panic came from inside the allocator
+pub fn stats() -> Option<HeapStats> {
+    // if it's locked and try_lock still works, the lock is ours and we are panicking inside it
+    let interrupted = crate::ALLOCATOR.0.is_locked();
+    let mut heap = crate::ALLOCATOR.0.try_lock()?;
+    let largest_free = (!interrupted).then(|| unsafe { heap.largest_free() });
+    Some(HeapStats {
+        arenas: heap.arenas.iter().flatten().count(),
+        claimed: heap.claimed,
+        in_use: heap.in_use,
+        peak: heap.peak,
+        failures: heap.failures,
+        oversized: heap.oversized,
+        largest_free,
+    })
+}
+
+/// give the static arena to the allocator, must run before anything allocates.
+/// more arenas are claimed from pmm on demand once it has been set up
+pub fn init() {
+    let mut heap = crate::ALLOCATOR.0.lock();
+    assert_eq!(heap.claimed, 0, "heap already initialised");
+    unsafe { heap.add_arena(Span::from_array(ptr::addr_of_mut!(INITIAL_ARENA))) };
+}
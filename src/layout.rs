@@ -0,0 +1,211 @@
+// layout.rs
+// the memory regions carved out by script.ld, and a boot time check that none of them overlap
+#![allow(dead_code)]
+use crate::println;
+use alloc::vec::Vec;
+use core::fmt;
+use log::*;
+
+/// size of the unmapped gap below every stack and the heap
+pub const GUARD_SIZE: usize = 0x1000;
+/// the stack the boot hart runs on until it has its own
+pub const INIT_STACK_SIZE: usize = 0x10000;
+/// the stack each hart gets once it is brought up
+pub const HART_STACK_SIZE: usize = 0x10000;
+/// the stack each hart switches to in the trap vector, see trap.rs
+pub const TRAP_STACK_SIZE: usize = 0x2000;
+/// how many per-hart stacks script.ld reserves
+pub const MAX_HARTS: usize = 8;
+
+extern "C" {
+    fn _kernel_start();
+    fn _rodata_start();
+    fn _data_start();
+    fn _kernel_end();
+    fn _init_stack_bottom();
+    fn _init_stack_top();
+    fn _hart_stacks_start();
+    fn _hart_stacks_end();
+    fn _trap_stacks_start();
+    fn _trap_stacks_end();
+    fn _heap_start();
+    fn _heap_end();
+    fn end();
+}
+
+/// a half open range of physical memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Region {
+    pub const fn new(name: &'static str, start: usize, end: usize) -> Self {
+        Self { name, start, end }
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    pub fn intersects(&self, other: &Region) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>12}: {:#010x}..{:#010x} ({} KiB)",
+            self.name,
+            self.start,
+            self.end,
+            self.size() / 1024
+        )
+    }
+}
+
+/// the kernel image, text through bss
+pub fn kernel_image() -> Region {
+    Region::new("kernel", _kernel_start as usize, _kernel_end as usize)
+}
+
+/// code, read and execute
+pub fn kernel_text() -> Region {
+    Region::new("text", _kernel_start as usize, _rodata_start as usize)
+}
+
+/// read only data
+pub fn kernel_rodata() -> Region {
+    Region::new("rodata", _rodata_start as usize, _data_start as usize)
+}
+
+/// .data and .bss, read and write
+pub fn kernel_data() -> Region {
+    Region::new("data", _data_start as usize, _kernel_end as usize)
+}
+
+pub fn init_stack() -> Region {
+    Region::new(
+        "init stack",
+        _init_stack_bottom as usize,
+        _init_stack_top as usize,
+    )
+}
+
+/// the stack for hart slot `index`, not including the guard page below it
+pub fn hart_stack(index: usize) -> Option<Region> {
+    if index >= MAX_HARTS {
+        return None;
+    }
+    let bottom = _hart_stacks_start as usize + index * (GUARD_SIZE + HART_STACK_SIZE) + GUARD_SIZE;
+    Some(Region::new("hart stack", bottom, bottom + HART_STACK_SIZE))
+}
+
+/// all the per-hart stacks together, guard pages included
+pub fn hart_stacks() -> Region {
+    Region::new(
+        "hart stacks",
+        _hart_stacks_start as usize,
+        _hart_stacks_end as usize,
+    )
+}
+
+/// the trap stack for hart slot `index`, not including the guard page below it
+pub fn trap_stack(index: usize) -> Option<Region> {
+    if index >= MAX_HARTS {
+        return None;
+    }
+    let bottom = _trap_stacks_start as usize + index * (GUARD_SIZE + TRAP_STACK_SIZE) + GUARD_SIZE;
+    Some(Region::new("trap stack", bottom, bottom + TRAP_STACK_SIZE))
+}
+
+/// all the trap stacks together, guard pages included
+pub fn trap_stacks() -> Region {
+    Region::new(
+        "trap stacks",
+        _trap_stacks_start as usize,
+        _trap_stacks_end as usize,
+    )
+}
+
+pub fn heap() -> Region {
+    Region::new("heap", _heap_start as usize, _heap_end as usize)
+}
+
+/// the stacks and the heap, each of which sits on top of a guard page.
+/// doesn't allocate, the trap handler goes through it
+pub fn guarded() -> impl Iterator<Item = Region> + Clone {
+    core::iter::once(init_stack())
+        .chain((0..MAX_HARTS).filter_map(hart_stack))
+        .chain((0..MAX_HARTS).filter_map(trap_stack))
+        .chain(core::iter::once(heap()))
+}
+
+/// the guard page below `region`
+pub fn guard_below(region: &Region) -> Region {
+    Region::new("guard", region.start - GUARD_SIZE, region.start)
+}
+
+/// the region whose guard page `addr` falls in, if any
+pub fn guarded_by(addr: usize) -> Option<Region> {
+    guarded().find(|region| guard_below(region).contains(addr))
+}
+
+/// everything script.ld reserved, from the image up to `end`, guard pages included
+pub fn kernel_reserved() -> Region {
+    Region::new("kernel+", _kernel_start as usize, end as usize)
+}
+
+/// make sure script.ld and this file agree, and that nothing we reserved overlaps anything
+/// else, including the FDT blob which is only known at runtime
+pub fn check(fdt: Region) {
+    assert_eq!(
+        init_stack().size(),
+        INIT_STACK_SIZE,
+        "script.ld and layout.rs disagree on the init stack size"
+    );
+    assert_eq!(
+        hart_stacks().size(),
+        MAX_HARTS * (GUARD_SIZE + HART_STACK_SIZE),
+        "script.ld and layout.rs disagree on the hart stacks"
+    );
+    assert_eq!(
+        trap_stacks().size(),
+        MAX_HARTS * (GUARD_SIZE + TRAP_STACK_SIZE),
+        "script.ld and layout.rs disagree on the trap stacks"
+    );
+
+    // everything but the image and the FDT should sit on top of its own guard page
+    let guarded: Vec<_> = guarded().collect();
+    let mut regions = guarded.clone();
+    regions.push(kernel_image());
+    regions.push(fdt);
+
+    for region in &regions {
+        info!("{}", region);
+    }
+    for (i, a) in regions.iter().enumerate() {
+        for b in regions.iter().skip(i + 1) {
+            if a.intersects(b) {
+                println!("{}", a);
+                println!("{}", b);
+                panic!("memory regions {} and {} overlap", a.name, b.name);
+            }
+        }
+    }
+    for region in &guarded {
+        let guard = guard_below(region);
+        if region.start % GUARD_SIZE != 0 || regions.iter().any(|other| other.intersects(&guard)) {
+            println!("{}", region);
+            panic!("{} has no guard page below it", region.name);
+        }
+    }
+}
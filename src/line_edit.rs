@@ -0,0 +1,417 @@
+// line_edit.rs
+// the line editor behind `readln!`: cursor movement, deleting, history and UTF-8 input,
+// drawn with plain VT100 sequences relative to where the cursor started
+#![allow(dead_code)]
+use crate::{sync::IrqSafeSpinlock, uart};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// lines kept for up/down recall
+const MAX_HISTORY: usize = 32;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_B: u8 = 0x02;
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_E: u8 = 0x05;
+const CTRL_F: u8 = 0x06;
+const BACKSPACE: u8 = 0x08;
+const CTRL_K: u8 = 0x0b;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+static HISTORY: IrqSafeSpinlock<VecDeque<String>> =
+    IrqSafeSpinlock::new("line history", VecDeque::new());
+/// the last line ended with `\r`, so a `\n` straight after it is part of the same enter
+static LAST_WAS_CR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    /// Ctrl-C, throw the line away
+    Interrupt,
+    /// Ctrl-U, delete up to the cursor
+    KillToStart,
+    /// Ctrl-K, delete from the cursor on
+    KillToEnd,
+    /// Ctrl-W, delete the word before the cursor
+    KillWord,
+    /// anything we don't handle
+    Ignored,
+}
+
+/// a byte that isn't a control character, and the continuation bytes that follow it if it
+/// starts a UTF-8 sequence
+fn decode_utf8(first: u8) -> Key {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        // a stray continuation byte or garbage
+        _ => return Key::Ignored,
+    };
+    let mut bytes = [first, 0, 0, 0];
+    for byte in bytes.iter_mut().take(len).skip(1) {
+        *byte = uart::receive();
+    }
+    core::str::from_utf8(&bytes[..len])
+        .ok()
+        .and_then(|s| s.chars().next())
+        .map_or(Key::Ignored, Key::Char)
+}
+
+/// what follows ESC: `[` or `O`, optional numeric parameters, then a final byte
+fn decode_escape() -> Key {
+    let intro = uart::receive();
+    if intro != b'[' && intro != b'O' {
+        return Key::Ignored;
+    }
+    let mut param = 0u32;
+    loop {
+        let byte = uart::receive();
+        match byte {
+            b'0'..=b'9' => param = param.saturating_mul(10) + (byte - b'0') as u32,
+            b';' => param = 0,
+            b'A' => return Key::Up,
+            b'B' => return Key::Down,
+            b'C' => return Key::Right,
+            b'D' => return Key::Left,
+            b'H' => return Key::Home,
+            b'F' => return Key::End,
+            b'~' => {
+                return match param {
+                    1 | 7 => Key::Home,
+                    3 => Key::Delete,
+                    4 | 8 => Key::End,
+                    _ => Key::Ignored,
+                }
+            }
+            // some other final byte, a key we don't do anything with
+            0x40..=0x7e => return Key::Ignored,
+            // intermediate and private parameter bytes
+            _ => {}
+        }
+    }
+}
+
+fn read_key() -> Key {
+    loop {
+        let byte = uart::receive();
+        let after_cr = LAST_WAS_CR.swap(byte == b'\r', Ordering::Relaxed);
+        return match byte {
+            b'\n' if after_cr => continue,
+            b'\r' | b'\n' => Key::Enter,
+            BACKSPACE | DEL => Key::Backspace,
+            CTRL_A => Key::Home,
+            CTRL_B => Key::Left,
+            CTRL_C => Key::Interrupt,
+            CTRL_D => Key::Delete,
+            CTRL_E => Key::End,
+            CTRL_F => Key::Right,
+            CTRL_K => Key::KillToEnd,
+            CTRL_U => Key::KillToStart,
+            CTRL_W => Key::KillWord,
+            ESC => decode_escape(),
+            0x00..=0x1f => Key::Ignored,
+            _ => decode_utf8(byte),
+        };
+    }
+}
+
+/// combining marks and zero width spaces, drawn on top of the char before them
+const ZERO_WIDTH: &[(u32, u32)] = &[
+    (0x0300, 0x036f),
+    (0x0483, 0x0489),
+    (0x0591, 0x05bd),
+    (0x0610, 0x061a),
+    (0x064b, 0x065f),
+    (0x1ab0, 0x1aff),
+    (0x1dc0, 0x1dff),
+    (0x200b, 0x200f),
+    (0x20d0, 0x20ff),
+    (0xfe00, 0xfe0f),
+    (0xfe20, 0xfe2f),
+];
+
+/// East Asian wide and fullwidth blocks, and the emoji, which take two columns
+const DOUBLE_WIDTH: &[(u32, u32)] = &[
+    (0x1100, 0x115f),
+    (0x2e80, 0x303e),
+    (0x3041, 0x33ff),
+    (0x3400, 0x4dbf),
+    (0x4e00, 0x9fff),
+    (0xa000, 0xa4cf),
+    (0xac00, 0xd7a3),
+    (0xf900, 0xfaff),
+    (0xfe30, 0xfe4f),
+    (0xff00, 0xff60),
+    (0xffe0, 0xffe6),
+    (0x1f300, 0x1f64f),
+    (0x1f900, 0x1f9ff),
+    (0x20000, 0x2fffd),
+    (0x30000, 0x3fffd),
+];
+
+/// how many columns the terminal moves on for `char`. only covers the common ranges, anything
+/// else is taken to be one column
+fn width(char: char) -> usize {
+    let within = |table: &[(u32, u32)]| {
+        table
+            .iter()
+            .any(|&(first, last)| (first..=last).contains(&(char as u32)))
+    };
+    if within(ZERO_WIDTH) {
+        0
+    } else if within(DOUBLE_WIDTH) {
+        2
+    } else {
+        1
+    }
+}
+
+/// the line being edited and what's on screen for it
+pub struct Editor {
+    line: Vec<char>,
+    /// in chars, 0..=line.len(). moving it steps over zero width chars along with the one before
+    cursor: usize,
+    /// which history entry is shown, counting back from the newest
+    history_pos: Option<usize>,
+    /// what was typed before browsing the history
+    stash: Vec<char>,
+    /// output gathered up and sent in one go after each key
+    out: String,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history_pos: None,
+            stash: Vec::new(),
+            out: String::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.out.is_empty() {
+            uart::print_raw(&self.out);
+            self.out.clear();
+        }
+    }
+
+    /// columns taken up by `line[from..to]`
+    fn columns(&self, from: usize, to: usize) -> usize {
+        self.line[from..to].iter().map(|&char| width(char)).sum()
+    }
+
+    /// cursor movements are in columns
+    fn move_left(&mut self, n: usize) {
+        if n > 0 {
+            let _ = write!(self.out, "\x1b[{}D", n);
+        }
+    }
+
+    fn move_right(&mut self, n: usize) {
+        if n > 0 {
+            let _ = write!(self.out, "\x1b[{}C", n);
+        }
+    }
+
+    /// draw everything from the cursor on, clear whatever was left past the end, then put the
+    /// cursor back
+    fn redraw_tail(&mut self) {
+        let tail = self.columns(self.cursor, self.line.len());
+        self.out.extend(&self.line[self.cursor..]);
+        self.out.push_str("\x1b[K");
+        self.move_left(tail);
+    }
+
+    fn insert(&mut self, char: char) {
+        self.line.insert(self.cursor, char);
+        self.out.push(char);
+        self.cursor += 1;
+        if self.cursor < self.line.len() {
+            self.redraw_tail();
+        }
+    }
+
+    /// remove `count` chars ending at the cursor
+    fn delete_before(&mut self, count: usize) {
+        let count = count.min(self.cursor);
+        if count == 0 {
+            return;
+        }
+        self.move_left(self.columns(self.cursor - count, self.cursor));
+        self.line.drain(self.cursor - count..self.cursor);
+        self.cursor -= count;
+        self.redraw_tail();
+    }
+
+    fn delete_after(&mut self, count: usize) {
+        let count = count.min(self.line.len() - self.cursor);
+        if count == 0 {
+            return;
+        }
+        self.line.drain(self.cursor..self.cursor + count);
+        self.redraw_tail();
+    }
+
+    fn set_cursor(&mut self, cursor: usize) {
+        if cursor < self.cursor {
+            self.move_left(self.columns(cursor, self.cursor));
+        } else {
+            self.move_right(self.columns(self.cursor, cursor));
+        }
+        self.cursor = cursor;
+    }
+
+    /// one char to the left or right, skipping over any zero width ones that go with it
+    fn step(&mut self, left: bool) {
+        let mut cursor = self.cursor;
+        if left {
+            cursor = cursor.saturating_sub(1);
+            while cursor > 0 && width(self.line[cursor]) == 0 {
+                cursor -= 1;
+            }
+        } else {
+            cursor = (cursor + 1).min(self.line.len());
+            while cursor < self.line.len() && width(self.line[cursor]) == 0 {
+                cursor += 1;
+            }
+        }
+        self.set_cursor(cursor);
+    }
+
+    /// swap the whole line for `line`, cursor at the end
+    fn replace(&mut self, line: Vec<char>) {
+        self.set_cursor(0);
+        self.line = line;
+        self.redraw_tail();
+        self.set_cursor(self.line.len());
+    }
+
+    /// length of the word before the cursor, and the spaces after it
+    fn word_before(&self) -> usize {
+        let before = &self.line[..self.cursor];
+        let spaces = before
+            .iter()
+            .rev()
+            .take_while(|c| c.is_whitespace())
+            .count();
+        let word = before[..before.len() - spaces]
+            .iter()
+            .rev()
+            .take_while(|c| !c.is_whitespace())
+            .count();
+        spaces + word
+    }
+
+    fn history(&mut self, older: bool) {
+        let history = HISTORY.lock();
+        let next = match (self.history_pos, older) {
+            (None, true) if !history.is_empty() => Some(0),
+            (Some(pos), true) if pos + 1 < history.len() => Some(pos + 1),
+            (Some(0), false) => None,
+            (Some(pos), false) => Some(pos - 1),
+            // already at the oldest, or not browsing
+            _ => return,
+        };
+        let line = match next {
+            Some(pos) => history[history.len() - 1 - pos].chars().collect(),
+            None => core::mem::take(&mut self.stash),
+        };
+        drop(history);
+        if self.history_pos.is_none() {
+            self.stash = self.line.clone();
+        }
+        self.history_pos = next;
+        self.replace(line);
+    }
+
+    /// handle one key, `Some` once the line is done
+    pub fn key(&mut self, key: Key) -> Option<String> {
+        match key {
+            Key::Char(char) => self.insert(char),
+            Key::Enter => {
+                self.set_cursor(self.line.len());
+                return Some(self.line.iter().collect());
+            }
+            Key::Backspace => self.delete_before(1),
+            Key::Delete => self.delete_after(1),
+            Key::Left => self.step(true),
+            Key::Right => self.step(false),
+            Key::Home => self.set_cursor(0),
+            Key::End => self.set_cursor(self.line.len()),
+            Key::Up => self.history(true),
+            Key::Down => self.history(false),
+            Key::Interrupt => {
+                self.set_cursor(self.line.len());
+                self.out.push_str("^C");
+                self.line.clear();
+                return Some(String::new());
+            }
+            Key::KillToStart => self.delete_before(self.cursor),
+            Key::KillToEnd => self.delete_after(self.line.len() - self.cursor),
+            Key::KillWord => self.delete_before(self.word_before()),
+            Key::Ignored => {}
+        }
+        None
+    }
+}
+
+/// remember `line` for up/down recall, skipping blanks and repeats
+fn add_history(line: &str) {
+    if line.trim().is_empty() {
+        return;
+    }
+    let mut history = HISTORY.lock();
+    if history.back().map_or(false, |last| last == line) {
+        return;
+    }
+    if history.len() == MAX_HISTORY {
+        history.pop_front();
+    }
+    history.push_back(line.into());
+}
+
+/// run `f` with `lines` standing in for the history, oldest first, for the selftests. the real
+/// history is put back afterwards
+pub fn with_history<R>(lines: &[&str], f: impl FnOnce() -> R) -> R {
+    let lines = lines.iter().map(|&line| line.into()).collect();
+    let saved = core::mem::replace(&mut *HISTORY.lock(), lines);
+    let result = f();
+    *HISTORY.lock() = saved;
+    result
+}
+
+/// read and edit a line from the console, without the line ending. Ctrl-C gives back an empty line
+pub fn read_line() -> String {
+    let mut editor = Editor::new();
+    let line = loop {
+        let key = read_key();
+        let done = editor.key(key);
+        editor.flush();
+        if let Some(line) = done {
+            break line;
+        }
+    };
+    crate::println!();
+    add_history(&line);
+    line
+}
@@ -0,0 +1,237 @@
+// logger.rs
+// the `log` backend: timestamped, colored lines on the console with per-module level filters,
+// and a copy of everything in a ring buffer that `dmesg` can print later
+#![allow(dead_code)]
+use crate::{
+    cmdline, println, smp,
+    sync::IrqSafeSpinlock,
+    term::{Color, Style},
+    time,
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::{self, Write},
+    time::Duration,
+};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// bytes of log text kept for `dmesg`
+const DMESG_SIZE: usize = 16 * 1024;
+/// the crate name at the front of every module path, left out when printing and matching
+const CRATE_PREFIX: &str = "oc2kernel::";
+
+/// level for modules without a filter of their own. `no_log` builds only report errors
+const DEFAULT_LEVEL: LevelFilter = if cfg!(feature = "no_log") {
+    LevelFilter::Error
+} else {
+    LevelFilter::Info
+};
+
+struct Filters {
+    default: LevelFilter,
+    /// module path (without the crate prefix) and its level, the longest matching prefix wins
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filters {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// the most verbose level anything is let through at, for `log::set_max_level`
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+static FILTERS: IrqSafeSpinlock<Filters> = IrqSafeSpinlock::new(
+    "log filters",
+    Filters {
+        default: DEFAULT_LEVEL,
+        modules: Vec::new(),
+    },
+);
+
+/// the last `DMESG_SIZE` bytes logged, oldest first once it has wrapped
+struct Dmesg {
+    buf: [u8; DMESG_SIZE],
+    /// where the next byte goes
+    head: usize,
+    wrapped: bool,
+}
+
+impl fmt::Write for Dmesg {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.head] = byte;
+            self.head += 1;
+            if self.head == DMESG_SIZE {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+static DMESG: IrqSafeSpinlock<Dmesg> = IrqSafeSpinlock::new(
+    "dmesg",
+    Dmesg {
+        buf: [0; DMESG_SIZE],
+        head: 0,
+        wrapped: false,
+    },
+);
+
+fn level_style(level: Level) -> Style {
+    match level {
+        Level::Error => Style::new().fg(Color::Red).bold(),
+        Level::Warn => Style::new().fg(Color::Yellow),
+        Level::Info => Style::new().fg(Color::Green),
+        Level::Debug => Style::new().fg(Color::Blue),
+        Level::Trace => Style::new().dim(),
+    }
+}
+
+/// `[   12.345678 h0] ` with the uptime in seconds, and the hart (`h?` before `tp` is set up)
+struct Prefix;
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // lines logged before the timebase is known show up at zero
+        let uptime = if time::timebase_frequency() == 0 {
+            Duration::ZERO
+        } else {
+            time::uptime()
+        };
+        write!(f, "[{:5}.{:06} ", uptime.as_secs(), uptime.subsec_micros())?;
+        match smp::try_hart_id() {
+            Some(hart) => write!(f, "h{}]", hart),
+            None => write!(f, "h?]"),
+        }
+    }
+}
+
+pub struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTERS.lock().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = record.level();
+        let target = record.target();
+        let module = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        let prefix = Prefix;
+        // the prefix is formatted twice, so the time can differ by a tick between the two copies
+        let _ = writeln!(
+            DMESG.lock(),
+            "{} {:5} {}: {}",
+            prefix,
+            level,
+            module,
+            record.args()
+        );
+        println!(
+            "{} {} {}: {}",
+            Style::new().dim().paint(prefix),
+            level_style(level).paint(format_args!("{:5}", level)),
+            module,
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// install the logger at the default level, as soon as the heap is up
+pub fn init() {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(FILTERS.lock().max());
+}
+
+/// set the levels from a spec like `info,virtio_hal=debug,pci=trace`: a bare level is the default,
+/// `module=level` applies to that module and everything under it. returns the parts that didn't parse
+pub fn configure(spec: &str) -> Vec<&str> {
+    let mut bad = Vec::new();
+    let mut filters = FILTERS.lock();
+    for part in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        match part.split_once('=') {
+            Some((module, level)) => match level.parse::<LevelFilter>() {
+                Ok(level) => {
+                    let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+                    filters.modules.retain(|(other, _)| other != module);
+                    filters.modules.push((module.into(), level));
+                }
+                Err(_) => bad.push(part),
+            },
+            None => match part.parse::<LevelFilter>() {
+                Ok(level) => filters.default = level,
+                Err(_) => bad.push(part),
+            },
+        }
+    }
+    log::set_max_level(filters.max());
+    bad
+}
+
+/// apply the `log=` option from the kernel command line
+pub fn configure_from_cmdline() {
+    let Some(spec) = cmdline::log() else {
+        return;
+    };
+    for bad in configure(spec) {
+        log::warn!("logger: ignoring `{}` in log={}", bad, spec);
+    }
+}
+
+/// hand every line still in the ring buffer to `f`, oldest first
+pub fn dmesg_lines(mut f: impl FnMut(&str)) {
+    let dmesg = DMESG.lock();
+    let (older, newer) = dmesg.buf.split_at(dmesg.head);
+    let mut text = Vec::with_capacity(DMESG_SIZE);
+    if dmesg.wrapped {
+        // the oldest line was partly overwritten, start at the next whole one
+        let start = newer
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(newer.len(), |i| i + 1);
+        text.extend_from_slice(&newer[start..]);
+    }
+    text.extend_from_slice(older);
+    // print outside the lock, printing might log
+    drop(dmesg);
+    for line in text
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        f(&String::from_utf8_lossy(line));
+    }
+}
+
+/// print the ring buffer to the console
+pub fn dmesg() {
+    dmesg_lines(|line| println!("{}", line));
+}
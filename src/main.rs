@@ -2,16 +2,15 @@
 #![no_main]
 #![feature(allocator_api)]
 #![feature(naked_functions)]
+#![feature(asm_const)]
 #![feature(const_mut_refs)]
 #![feature(panic_info_message)]
 #![feature(stdsimd)]
 #![cfg_attr(debug_assertions, feature(core_intrinsics))]
 
 use alloc::format;
-use bar32alloc::PciMemory32Allocator;
+use alloc::string::String;
 use core::arch::asm;
-use log::*;
-use uart::UartLogger;
 use virtio_drivers::{
     device::{
         blk::{VirtIOBlk, SECTOR_SIZE},
@@ -20,44 +19,37 @@ use virtio_drivers::{
     transport::{
         mmio::{MmioTransport, VirtIOHeader},
         pci::{
-            bus::{BarInfo, Cam, Command, DeviceFunction, MemoryBarType, PciRoot},
+            bus::{Cam, PciRoot},
             virtio_device_type, PciTransport,
         },
         Transport,
     },
 };
 
-//use talc::*;
-//use core::alloc::{Allocator, Layout};
-//static mut ARENA: [u8; 10000] = [0; 10000];
-//#[global_allocator]
-//static ALLOCATOR: Talck<spin::Mutex<()>, InitOnOom> = Talc::new(unsafe {
-//    // if we're in a hosted environment, the Rust runtime may allocate before
-//    // main() is called, so we need to initialize the arena automatically
-//    InitOnOom::new(Span::from_slice(ARENA.as_slice() as *const [u8] as *mut [u8]))
-//}).lock();
-
 //allocator so that we can use alloc variables
-use simple_chunk_allocator::{heap, heap_bitmap, GlobalChunkAllocator, PageAligned};
-static mut HEAP: PageAligned<[u8; 2097152]> = heap!(chunks = 2048, chunksize = 1024);
-static mut HEAP_BITMAP: PageAligned<[u8; 1024]> = heap_bitmap!(chunks = 8192);
 #[global_allocator]
-static ALLOCATOR: GlobalChunkAllocator =
-    unsafe { GlobalChunkAllocator::new(HEAP.deref_mut_const(), HEAP_BITMAP.deref_mut_const()) };
+static ALLOCATOR: heap::KernelHeap = heap::KernelHeap::new();
 
 //globals that we init on start so that we can use them anywhere
-// once cell? (probally should)
-static mut UART_BASE: *mut u8 = 0x1000_0148 as *mut u8;
-static mut DEVICE_TREE_PTR: *const u8 = 0x0 as *const u8;
-static LOGGER: UartLogger = UartLogger {};
+static UART_BASE: Once<usize> = Once::new();
+static DEVICE_TREE_PTR: Once<usize> = Once::new();
 //imports
 //basic rust things
 extern crate alloc;
 use crate::{
+    layout::Region,
+    pci::ConfigSpace,
+    sync::Once,
     uart::init_from_mmio,
-    virtio_hal::{HalImpl, ALLOC_PAGES, OPEN_PAGES},
+    virtio_async::{AsyncBlk, AsyncConsole},
+    virtio_hal::{HalImpl, DMA_INITIAL_PAGES, DMA_MAX_PAGES},
+    virtio_irq::{IrqAck, VirtioIrq},
+};
+use core::{
+    panic::PanicInfo,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use core::{panic::PanicInfo, ptr::NonNull, sync::atomic::Ordering};
 
 //entrypoint
 #[naked]
@@ -72,6 +64,15 @@ unsafe extern "C" fn _start() -> ! {
         "la gp, _global_pointer",
         ".option pop",
 
+        // the first hart in wins, the rest wait in {park} until start_secondaries
+        // releases them. under a firmware only one hart ever gets here
+        "la      t0, {claimed}",
+        "li      t1, 1",
+        "amoswap.w t1, t1, (t0)",
+        "beqz    t1, 2f",
+        "tail    {park}",
+    "2:",
+
         // set the stack pointer
         "la sp, _init_stack_top",
 
@@ -87,62 +88,12 @@ unsafe extern "C" fn _start() -> ! {
         // "tail-call" to {entry} (call without saving a return address)
         "tail {entry}",
         entry = sym entry, // {entry} refers to the function [entry] below
+        claimed = sym smp::BOOT_CLAIMED,
+        park = sym smp::_park,
         options(noreturn) // we must handle "returning" from assembly
       );
 }
 
-fn allocate_bars(
-    root: &mut PciRoot,
-    device_function: DeviceFunction,
-    allocator: &mut PciMemory32Allocator,
-) {
-    let mut bar_index = 0;
-    while bar_index < 6 {
-        let info = root.bar_info(device_function, bar_index).unwrap();
-        debug!("BAR {}: {}", bar_index, info);
-        // Ignore I/O bars, as they aren't required for the VirtIO driver.
-        if let BarInfo::Memory {
-            address_type, size, ..
-        } = info
-        {
-            match address_type {
-                MemoryBarType::Width32 => {
-                    if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
-                        debug!("Allocated address {:#010x}", address);
-                        root.set_bar_32(device_function, bar_index, address);
-                    }
-                }
-                MemoryBarType::Width64 => {
-                    if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
-                        debug!("Allocated address {:#010x}", address);
-                        root.set_bar_64(device_function, bar_index, address.into());
-                    }
-                }
-
-                _ => panic!("Memory BAR address type {:?} not supported.", address_type),
-            }
-        }
-
-        bar_index += 1;
-        if info.takes_two_entries() {
-            bar_index += 1;
-        }
-    }
-
-    // Enable the device to use its BARs.
-    root.set_command(
-        device_function,
-        Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
-    );
-    let (status, command) = root.get_status_command(device_function);
-    debug!(
-        "Allocated BARs and enabled device, status {:?} command {:?}",
-        status, command
-    );
-}
-
 #[allow(dead_code)]
 fn write_block<T>(block: &mut VirtIOBlk<HalImpl, T>, data: &[u8], page: usize) -> usize
 where
@@ -163,33 +114,59 @@ where
 }
 
 /// now we can start cooking, our real code exist here
-extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
+extern "C" fn entry(hart_id: u64, fdt_ptr: *const u8) -> ! {
     unsafe {
-        let _ = log::set_logger(&LOGGER);
-        if cfg!(feature = "no_log") {
-            log::set_max_level(log::LevelFilter::Info);
-        } else {
-            log::set_max_level(log::LevelFilter::Warn);
-        }
-        //init the virtio hal as `lazy_static` doesen't exist
-        virtio_hal::init_virtio_hal();
-
+        smp::init_boot_hart(hart_id as usize); //point tp at our per-hart data
+        heap::init(); //nothing may allocate before this
+        logger::init();
         //setup the globals
-        DEVICE_TREE_PTR = fdt_ptr; //device tree ptr
+        DEVICE_TREE_PTR.call_once(|| fdt_ptr as usize); //device tree ptr
         let dev_tree = fdt::Fdt::from_ptr(fdt_ptr).expect("fdt pointer no exist?");
         //get Uart base addr
-        UART_BASE = dev_tree //uart base ptr
-            .chosen()
-            .stdout()
-            .unwrap()
-            .reg()
-            .unwrap()
-            .next()
-            .unwrap()
-            .starting_address
-            .cast_mut();
-        init_from_mmio(UART_BASE as usize); //setup the UART for terminal output
-                                            //the real program
+        let stdout = dev_tree.chosen().stdout().unwrap();
+        let uart_base = UART_BASE.call_once(|| {
+            stdout //uart base ptr
+                .reg()
+                .unwrap()
+                .next()
+                .unwrap()
+                .starting_address as usize
+        });
+        let reg_shift = stdout
+            .property("reg-shift")
+            .and_then(|prop| prop.as_usize())
+            .unwrap_or(0);
+        init_from_mmio(*uart_base, reg_shift); //setup the UART for terminal output
+        cmdline::init(&dev_tree); //bootargs, log=, console=, root= and selftest
+        logger::configure_from_cmdline();
+        trap::init(); //install the trap vector so faults and interrupts get reported
+        platform::init(&dev_tree); //SBI firmware in S-mode, CLINT and syscon in M-mode
+        plic::init(&dev_tree); //find our plic context from the device tree
+        uart::init_irq(&dev_tree); //console input and output go through the UART interrupt now
+        time::init(&dev_tree); //timebase and timer interrupts
+        uart::configure_from_cmdline(); //term.wrap= and term.colors=, asks the terminal its size
+        let fdt_region = Region::new(
+            "fdt",
+            fdt_ptr as usize,
+            fdt_ptr as usize + dev_tree.total_size(),
+        );
+        //make sure the stacks, heap and device tree all stay out of each others way
+        layout::check(fdt_region);
+        //hand the rest of RAM to the frame allocator
+        pmm::init(&dev_tree, fdt_region);
+        //identity map the kernel with per-section permissions, leaving the guard pages out
+        paging::init(&dev_tree);
+        //the DMA pool for virtio grows out of the frame allocator
+        virtio_hal::init_virtio_hal(DMA_INITIAL_PAGES, DMA_MAX_PAGES);
+        //wake the other harts now that memory and paging are settled
+        smp::start_secondaries(&dev_tree);
+        //from here on this is the "main" task, anything spawned shares the hart with it
+        sched::init_hart("main");
+        if cmdline::selftest() && !selftest::run() {
+            panic!("self-tests failed");
+        }
+
+        //the real program
         println!();
         println!("Hello, World");
         println!("cpu count: {}", dev_tree.cpus().count());
@@ -208,31 +185,47 @@ extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
             .unwrap();
         let pci_addr = pci_node.reg().unwrap().next().unwrap().starting_address;
         let mut pci = PciRoot::new(pci_addr as *mut u8, Cam::Ecam);
-        let mut allocator = PciMemory32Allocator::for_pci_ranges(&pci_node);
+        let pci_config = ConfigSpace::new(pci_addr as usize);
+        // without windows to put BARs in there's nothing to enable, and no PCI console either
+        match pci_enum::Allocators::for_pci_ranges(&pci_node) {
+            Ok(mut allocators) => {
+                let pci_tree = pci_enum::enumerate(
+                    &mut pci,
+                    &pci_config,
+                    &mut allocators,
+                    pci_enum::bus_range(&pci_node),
+                );
+                pci::init(&pci, &pci_config, &pci_tree, |bar| {
+                    allocators.cpu_address(bar)
+                });
+                pci::print_devices(cmdline::lspci());
+            }
+            Err(err) => println!("PCI: {}, not enumerating", err),
+        }
         #[allow(unused_mut, unused_variables)]
-        let mut console: Option<VirtIOConsole<HalImpl, PciTransport>> = {
+        let mut console: Option<(
+            VirtIOConsole<HalImpl, PciTransport>,
+            Option<&VirtioIrq>,
+        )> = {
             let mut ret = None;
-            for a in 0..255 {
-                for (i, j) in pci.enumerate_bus(a) {
-                    println!("pci device: {:#X}:{:#X}", j.vendor_id, j.device_id);
-                    if let Some(dev_type) = virtio_device_type(&j) {
-                        println!("devtype: {:?}", dev_type);
-                        pci.set_command(
-                            i,
-                            Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER,
-                        );
-                        allocate_bars(&mut pci, i, &mut allocator);
-                        println!("{:?}", pci.bar_info(i, 4));
-                        let console = PciTransport::new::<HalImpl>(&mut pci, i);
-                        match console {
-                            Ok(pcit) => {
-                                println!("virtio_type {:?}", pcit.device_type());
-                                ret = Some(VirtIOConsole::new(pcit).unwrap());
-                                break;
-                            }
-                            Err(e) => {
-                                println!("error {:?}", e);
-                            }
+            for device in pci::devices().iter().filter(|device| device.enabled) {
+                let i = device.device_function;
+                if let Some(dev_type) = virtio_device_type(&device.info) {
+                    println!("devtype: {:?}", dev_type);
+                    println!("{:?}", device.bars.iter().find(|bar| bar.index == 4));
+                    let console = PciTransport::new::<HalImpl>(&mut pci, i);
+                    match console {
+                        Ok(pcit) => {
+                            println!("virtio_type {:?}", pcit.device_type());
+                            //hook up the INTx line so reads can sleep instead of spin
+                            let irq = device.irq.zip(pci_config.virtio_isr_address(&pci, i));
+                            let irq =
+                                irq.map(|(irq, isr)| VirtioIrq::register(irq, IrqAck::PciIsr(isr)));
+                            ret = Some((VirtIOConsole::new(pcit).unwrap(), irq));
+                            break;
+                        }
+                        Err(e) => {
+                            println!("error {:?}", e);
                         }
                     }
                 }
@@ -274,6 +267,10 @@ extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
                 .offset(8) as *const u32;
             println!("virt: {} d:{}", virt.name, *device);
             if *device == 2 {
+                if !cmdline::is_root(virt.name) {
+                    println!("not the root device, skipping");
+                    continue;
+                }
                 println!("device type confirmed");
                 //we got ourselfes a storage device
                 let header = NonNull::new(
@@ -283,8 +280,15 @@ extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
                 println!("got header");
                 let transport = MmioTransport::new(header).unwrap();
                 println!("transport created");
-                let mut ublk = VirtIOBlk::<HalImpl, _>::new(transport).unwrap();
+                let ublk = VirtIOBlk::<HalImpl, _>::new(transport).unwrap();
                 println!("connected to block");
+                //virtio-mmio devices get their own interrupt line (1..8 on QEMU virt)
+                let irq = virt
+                    .interrupts()
+                    .and_then(|mut irqs| irqs.next())
+                    .map(|irq| {
+                        VirtioIrq::register(irq as u32, IrqAck::Mmio(header.as_ptr() as usize))
+                    });
                 let blcks = ublk.capacity();
                 println!("Blocks: {}", blcks);
                 println!("Size: {} bytes", (blcks * (SECTOR_SIZE as u64)));
@@ -295,7 +299,8 @@ extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
                 let bstr = b"DEBUG World!!!";
                 let mut buf = [0u8; SECTOR_SIZE];
                 buf[..bstr.len()].copy_from_slice(bstr.as_slice());
-                let _ = ublk.write_blocks(0, &buf);
+                let mut ablk = AsyncBlk::new(ublk, irq);
+                let _ = executor::block_on(ablk.write(0, &buf));
             } else if *device == 19 {
                 println!("HERES OUR SERIAL")
             } else if let Ok(miot) =
@@ -304,8 +309,19 @@ extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
                 println!("other device type {:?}", miot.device_type());
             }
         }
-        print!("gimme string>");
-        let str = readln!();
+        let str = match console {
+            Some((con, irq)) if cmdline::console() == cmdline::Console::Virtio => {
+                let mut con = AsyncConsole::new(con, irq);
+                virtio_send(&mut con, "gimme string>");
+                let str = executor::block_on(virtio_read_line(&mut con));
+                virtio_send(&mut con, "\r\n");
+                str
+            }
+            _ => {
+                print!("gimme string>");
+                readln!()
+            }
+        };
         println!("here you go: {}", str);
         println!("goodbye");
 
@@ -313,9 +329,73 @@ extern "C" fn entry(_hard_id: u64, fdt_ptr: *const u8) -> ! {
     }
 }
 
+fn virtio_send<T: Transport>(con: &mut AsyncConsole<T>, str: &str) {
+    for byte in str.bytes() {
+        let _ = con.send(byte);
+    }
+}
+
+/// a line from the virtio console, echoed back as it's typed
+async fn virtio_read_line<T: Transport>(con: &mut AsyncConsole<T>) -> String {
+    let mut line = String::new();
+    loop {
+        match con.recv().await {
+            Ok(b'\r' | b'\n') | Err(_) => return line,
+            Ok(0x08 | 0x7f) => {
+                if line.pop().is_some() {
+                    virtio_send(con, "\x08 \x08");
+                }
+            }
+            Ok(byte) if byte.is_ascii() && !byte.is_ascii_control() => {
+                line.push(byte as char);
+                let _ = con.send(byte);
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+fn print_memory_usage() {
+    match heap::stats() {
+        Some(stats) => println!("{}", stats),
+        None => println!("heap: allocator locked, allocation in progress"),
+    }
+    match virtio_hal::dma_usage() {
+        Some(usage) => println!("{}", usage),
+        None => println!("DMA pages: pool locked, allocation in progress"),
+    }
+    match pmm::stats() {
+        Some(stats) => println!("{}", stats),
+        None => println!("frames: allocator locked, allocation in progress"),
+    }
+}
+
+/// what both panic handlers do once the message is out: the registers if a trap raised it,
+/// a backtrace, memory usage, then whatever `panic=` on the command line asks for
+fn after_panic() -> ! {
+    static NESTED: AtomicBool = AtomicBool::new(false);
+    //a panic while reporting a panic skips straight to the end
+    if !NESTED.swap(true, Ordering::Relaxed) {
+        if let Some(frame) = trap::take_panic_frame() {
+            println!("{}", frame);
+            println!("trapped at {}", backtrace::Symbolized(frame.pc));
+        }
+        backtrace::print();
+        print_memory_usage();
+    }
+    match cmdline::panic_action() {
+        cmdline::PanicAction::PowerOff => platform::shutdown(),
+        cmdline::PanicAction::Reboot => platform::reboot(),
+        cmdline::PanicAction::Halt => loop {
+            unsafe { asm!("wfi") }
+        },
+    }
+}
+
 #[cfg(debug_assertions)]
 #[panic_handler]
 fn on_panic(info: &PanicInfo) -> ! {
+    sync::begin_panic(); //the locks this hart holds are ours to use now
     let location = info.location().unwrap();
     let file = location.file();
     let line = location.line();
@@ -326,26 +406,46 @@ fn on_panic(info: &PanicInfo) -> ! {
     print!("{}@", file); //panic only the file and line
     print!("{}: ", line);
     println!("{}", err_debug);
-    println!("DMA pages: {:064b}", OPEN_PAGES.load(Ordering::Relaxed));
-    println!("Alloc pages: {}", ALLOC_PAGES.load(Ordering::Relaxed));
-    loop {
-        unsafe { asm!("wfi") }
-    }
+    after_panic()
 }
 
 #[cfg(not(debug_assertions))]
 #[panic_handler]
 fn on_panic(info: &PanicInfo) -> ! {
+    sync::begin_panic(); //the locks this hart holds are ours to use now
     println!("{}", info);
-    println!("DMA pages: {:064b}", OPEN_PAGES.load(Ordering::Relaxed));
-    println!("Alloc pages: {}", ALLOC_PAGES.load(Ordering::Relaxed));
     //break-here
-    loop {
-        unsafe { asm!("wfi") }
-    }
+    after_panic()
 }
 
+mod backtrace;
 mod bar32alloc;
+mod bar64alloc;
+mod barioalloc;
+mod clint;
+mod cmdline;
+mod csr;
+mod executor;
+mod heap;
+mod layout;
+mod line_edit;
+mod logger;
+mod ns16550;
+mod paging;
+mod pci;
+mod pci_enum;
+mod platform;
 mod plic;
+mod pmm;
+mod sbi;
+mod sched;
+mod selftest;
+mod smp;
+mod sync;
+mod term;
+mod time;
+mod trap;
 mod uart;
+mod virtio_async;
 mod virtio_hal;
+mod virtio_irq;
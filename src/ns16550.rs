@@ -0,0 +1,366 @@
+// ns16550.rs
+// interrupt driven 16550 driver. received bytes go into a lock-free ring from the interrupt handler,
+// bytes to send wait in a queue that the THR empty interrupt drains
+#![allow(dead_code)]
+use crate::{
+    csr,
+    plic::{self, IrqHandler},
+    sync::{IrqSafeSpinlock, Once},
+};
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+use fdt::Fdt;
+use log::*;
+
+// register offsets, in units of the register stride
+const RBR: usize = 0; // receive buffer (read)
+const THR: usize = 0; // transmit holding (write)
+const DLL: usize = 0; // divisor latch low (DLAB set)
+const IER: usize = 1; // interrupt enable
+const DLM: usize = 1; // divisor latch high (DLAB set)
+const IIR: usize = 2; // interrupt identification (read)
+const FCR: usize = 2; // fifo control (write)
+const LCR: usize = 3; // line control
+const MCR: usize = 4; // modem control
+const LSR: usize = 5; // line status
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+/// set in IIR when no interrupt is pending
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+/// enable and clear both fifos, interrupt once 14 bytes are waiting
+const FCR_ENABLE: u8 = 0xc7;
+/// DTR, RTS and OUT2 (which gates the interrupt line on PC style boards)
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+/// bytes the transmit fifo takes once THR empty is set
+const TX_FIFO_SIZE: usize = 16;
+/// 38400 baud from the 1.8432MHz reference clock, QEMU doesn't care
+const DIVISOR: u16 = 3;
+
+const RX_SIZE: usize = 1024;
+const TX_SIZE: usize = 4096;
+/// priority given to the console interrupt
+const UART_PRIORITY: u8 = 1;
+
+/// single producer (the interrupt handler) ring, any hart can take bytes out
+struct RxRing {
+    buf: [AtomicU8; RX_SIZE],
+    /// bytes pushed ever, the next one goes at `head % RX_SIZE`
+    head: AtomicUsize,
+    /// bytes taken ever
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl RxRing {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicU8 = AtomicU8::new(0);
+        Self {
+            buf: [EMPTY; RX_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// only ever called from the interrupt handler
+    fn push(&self, byte: u8) {
+        let head = self.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= RX_SIZE {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.buf[head % RX_SIZE].store(byte, Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    fn pop(&self) -> Option<u8> {
+        loop {
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail == self.head.load(Ordering::Acquire) {
+                return None;
+            }
+            // the slot can't be reused until `tail` moves past it, so read it before claiming it
+            let byte = self.buf[tail % RX_SIZE].load(Ordering::Relaxed);
+            if self
+                .tail
+                .compare_exchange(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(byte);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.head
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.tail.load(Ordering::Acquire))
+    }
+}
+
+/// bytes waiting for the transmitter, always under `Ns16550::tx`
+struct TxQueue {
+    buf: [u8; TX_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl TxQueue {
+    const fn new() -> Self {
+        Self {
+            buf: [0; TX_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == TX_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % TX_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % TX_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+pub struct Ns16550 {
+    base: usize,
+    /// log2 of the register stride, `reg-shift` in the device tree
+    shift: usize,
+    /// PLIC id, 0 until `enable_interrupts`
+    irq: AtomicU32,
+    rx: RxRing,
+    tx: IrqSafeSpinlock<TxQueue>,
+    overruns: AtomicUsize,
+}
+
+/// counters for the console
+#[derive(Copy, Clone, Debug)]
+pub struct SerialStats {
+    pub irq: Option<u32>,
+    /// received bytes that haven't been read yet
+    pub rx_pending: usize,
+    /// received bytes lost because nobody read the ring in time
+    pub rx_dropped: usize,
+    /// bytes the hardware lost before we got to them
+    pub overruns: usize,
+    pub tx_pending: usize,
+}
+
+impl Ns16550 {
+    fn new(base: usize, shift: usize) -> Self {
+        Self {
+            base,
+            shift,
+            irq: AtomicU32::new(0),
+            rx: RxRing::new(),
+            tx: IrqSafeSpinlock::new("uart tx", TxQueue::new()),
+            overruns: AtomicUsize::new(0),
+        }
+    }
+
+    fn read(&self, reg: usize) -> u8 {
+        unsafe { ((self.base + (reg << self.shift)) as *const u8).read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u8) {
+        unsafe { ((self.base + (reg << self.shift)) as *mut u8).write_volatile(value) }
+    }
+
+    /// 8N1, fifos on, every interrupt off
+    fn reset(&self) {
+        self.write(IER, 0);
+        self.write(LCR, LCR_DLAB);
+        self.write(DLL, DIVISOR as u8);
+        self.write(DLM, (DIVISOR >> 8) as u8);
+        self.write(LCR, LCR_8N1);
+        self.write(FCR, FCR_ENABLE);
+        self.write(MCR, MCR_DTR_RTS_OUT2);
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn irq(&self) -> Option<u32> {
+        let irq = self.irq.load(Ordering::Relaxed);
+        (irq != 0).then_some(irq)
+    }
+
+    /// move whatever the receiver holds into the ring
+    fn drain_rx(&self) {
+        loop {
+            let lsr = self.read(LSR);
+            if lsr & LSR_OVERRUN != 0 {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+            if lsr & LSR_DATA_READY == 0 {
+                break;
+            }
+            self.rx.push(self.read(RBR));
+        }
+    }
+
+    /// fill the transmit fifo from the queue, and have the THR empty interrupt come back for the rest
+    fn drain_tx(&self, tx: &mut TxQueue) {
+        if tx.len > 0 && self.read(LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..TX_FIFO_SIZE {
+                let Some(byte) = tx.pop() else {
+                    break;
+                };
+                self.write(THR, byte);
+            }
+        }
+        if self.irq().is_some() {
+            let ier = self.read(IER);
+            let wanted = if tx.len > 0 {
+                ier | IER_THR_EMPTY
+            } else {
+                ier & !IER_THR_EMPTY
+            };
+            if wanted != ier {
+                self.write(IER, wanted);
+            }
+        }
+    }
+
+    /// the next received byte, if there is one
+    pub fn try_read(&self) -> Option<u8> {
+        if self.irq().is_none() {
+            // nothing fills the ring for us, go straight to the hardware
+            return (self.read(LSR) & LSR_DATA_READY != 0).then(|| self.read(RBR));
+        }
+        self.rx.pop()
+    }
+
+    /// queue as much of `bytes` as fits, returns how many were taken
+    pub fn write_bytes(&self, bytes: &[u8]) -> usize {
+        let mut tx = self.tx.lock();
+        let taken = bytes.iter().take_while(|&&byte| tx.push(byte)).count();
+        self.drain_tx(&mut tx);
+        taken
+    }
+
+    /// queue all of `bytes`, waiting for room when the queue is full. without interrupts
+    /// (early boot, panics) this is the same as writing them out by polling
+    pub fn write_all(&self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let taken = self.write_bytes(bytes);
+            bytes = &bytes[taken..];
+            if taken == 0 {
+                spin_loop();
+            }
+        }
+    }
+
+    /// wait for everything queued to go out
+    pub fn flush(&self) {
+        loop {
+            let mut tx = self.tx.lock();
+            if tx.len == 0 {
+                break;
+            }
+            self.drain_tx(&mut tx);
+            drop(tx);
+            spin_loop();
+        }
+    }
+
+    /// write one byte straight to the hardware, skipping the queue and its lock.
+    /// for reporting a hart stuck on a lock, the output lands in the middle of whatever is queued
+    pub fn write_raw(&self, byte: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {
+            spin_loop();
+        }
+        self.write(THR, byte);
+    }
+
+    pub fn stats(&self) -> SerialStats {
+        SerialStats {
+            irq: self.irq(),
+            rx_pending: self.rx.len(),
+            rx_dropped: self.rx.dropped.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            tx_pending: self.tx.try_lock().map_or(0, |tx| tx.len),
+        }
+    }
+}
+
+impl IrqHandler for Ns16550 {
+    fn handle(&self, _id: u32) -> bool {
+        let mut handled = false;
+        while self.read(IIR) & IIR_NO_INTERRUPT == 0 {
+            handled = true;
+            self.drain_rx();
+            self.drain_tx(&mut self.tx.lock());
+        }
+        handled
+    }
+}
+
+static PORT: Once<Ns16550> = Once::new();
+
+/// the console port, once `init` has run
+pub fn port() -> Option<&'static Ns16550> {
+    PORT.get()
+}
+
+/// set up the 16550 at `base` with polled I/O, interrupts come later with `enable_interrupts`
+pub fn init(base: usize, shift: usize) -> &'static Ns16550 {
+    PORT.call_once(|| {
+        let port = Ns16550::new(base, shift);
+        port.reset();
+        port
+    })
+}
+
+/// hook the port up to the interrupt in the `/chosen` stdout node. needs the PLIC
+pub fn enable_interrupts(fdt: &Fdt) -> bool {
+    let Some(port) = port() else {
+        return false;
+    };
+    let irq = fdt
+        .chosen()
+        .stdout()
+        .and_then(|stdout| stdout.interrupts())
+        .and_then(|mut irqs| irqs.next());
+    let Some(irq) = irq else {
+        warn!("ns16550: stdout has no interrupts property, staying polled");
+        return false;
+    };
+    // the handler is the only thing allowed to fill the ring, so keep it out until we're done
+    let was_enabled = csr::disable_interrupts();
+    port.irq.store(irq as u32, Ordering::Relaxed);
+    plic::register_handler(irq as u32, UART_PRIORITY, port);
+    port.write(IER, IER_RX_AVAILABLE);
+    // anything that came in while we were polling
+    port.drain_rx();
+    csr::restore_interrupts(was_enabled);
+    info!("ns16550: {:#x} on interrupt {}", port.base(), irq);
+    true
+}
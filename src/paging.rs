@@ -0,0 +1,441 @@
+// paging.rs
+// Sv39 page tables. the kernel is identity mapped with per-section permissions, guard pages are left unmapped
+#![allow(dead_code)]
+use crate::{
+    csr::{self, Mode},
+    csr_write,
+    layout::{self, Region},
+    pmm::{self, PAGE_SIZE},
+    smp::{self, IpiMessage},
+    sync::IrqSafeSpinlock,
+};
+use alloc::{vec, vec::Vec};
+use bitflags::bitflags;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use fdt::Fdt;
+use log::*;
+use spin::Once;
+
+bitflags! {
+    /// the low bits of a page table entry
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    pub struct PteFlags: u64 {
+        const VALID = 1 << 0;
+        const READ = 1 << 1;
+        const WRITE = 1 << 2;
+        const EXECUTE = 1 << 3;
+        const USER = 1 << 4;
+        const GLOBAL = 1 << 5;
+        const ACCESSED = 1 << 6;
+        const DIRTY = 1 << 7;
+        /// Svpbmt: non-cacheable, idempotent memory
+        const PBMT_NC = 1 << 61;
+        /// Svpbmt: non-cacheable, strongly ordered I/O
+        const PBMT_IO = 1 << 62;
+    }
+}
+
+impl PteFlags {
+    /// kernel code
+    pub const TEXT: Self = Self::READ.union(Self::EXECUTE);
+    /// constants
+    pub const RODATA: Self = Self::READ;
+    /// data, bss, stacks, the heap and the rest of RAM
+    pub const DATA: Self = Self::READ.union(Self::WRITE);
+    /// device registers, `PBMT_IO` is added when the cpu supports it
+    pub const MMIO: Self = Self::READ.union(Self::WRITE);
+
+    fn is_leaf(self) -> bool {
+        self.intersects(Self::READ | Self::WRITE | Self::EXECUTE)
+    }
+}
+
+/// Sv39 mode in `satp`
+const SATP_SV39: usize = 8 << 60;
+const ENTRIES: usize = 512;
+const LEVELS: usize = 3;
+/// bits 10..54 of an entry hold the physical page number
+const PPN_MASK: u64 = ((1 << 44) - 1) << 10;
+/// identity mapping only works below this, Sv39 addresses above it must be sign extended
+const VA_LIMIT: usize = 1 << 38;
+
+/// bytes mapped by one entry at `level`, 0 being the 4KiB leaves
+const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+const fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + 9 * level)) % ENTRIES
+}
+
+#[derive(Copy, Clone)]
+struct Entry(u64);
+
+impl Entry {
+    fn flags(self) -> PteFlags {
+        PteFlags::from_bits_truncate(self.0)
+    }
+
+    fn is_valid(self) -> bool {
+        self.flags().contains(PteFlags::VALID)
+    }
+
+    fn address(self) -> usize {
+        (((self.0 & PPN_MASK) >> 10) as usize) << 12
+    }
+
+    fn new(paddr: usize, flags: PteFlags) -> Self {
+        Self(((paddr as u64 >> 12) << 10) | (flags | PteFlags::VALID).bits())
+    }
+}
+
+/// a set of page tables, all allocated from pmm
+pub struct AddressSpace {
+    root: usize,
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            root: Self::new_table()?,
+        })
+    }
+
+    /// a zeroed page for a table
+    fn new_table() -> Option<usize> {
+        let table = pmm::alloc(0)?;
+        unsafe { core::ptr::write_bytes(table as *mut u8, 0, PAGE_SIZE) };
+        Some(table)
+    }
+
+    fn entry(table: usize, index: usize) -> *mut Entry {
+        (table as *mut Entry).wrapping_add(index)
+    }
+
+    /// value for `satp` that switches to these tables
+    pub fn satp(&self) -> usize {
+        SATP_SV39 | (self.root >> 12)
+    }
+
+    /// map one page (or huge page) at `level`, creating tables on the way down
+    fn map_page(&mut self, vaddr: usize, paddr: usize, level: usize, flags: PteFlags) -> bool {
+        let mut table = self.root;
+        for current in (level + 1..LEVELS).rev() {
+            let entry = Self::entry(table, vpn(vaddr, current));
+            let existing = unsafe { *entry };
+            table = if !existing.is_valid() {
+                let Some(next) = Self::new_table() else {
+                    return false;
+                };
+                unsafe { *entry = Entry::new(next, PteFlags::empty()) };
+                next
+            } else if existing.flags().is_leaf() {
+                // fine if a huge page already maps it the same way, e.g. overlapping device windows
+                let same = existing.address() + vaddr % level_size(current) == paddr;
+                if !same {
+                    warn!("paging: {:#x} is already covered by a huge page", vaddr);
+                }
+                return same;
+            } else {
+                existing.address()
+            };
+        }
+        let entry = Self::entry(table, vpn(vaddr, level));
+        let existing = unsafe { *entry };
+        if existing.is_valid() && !existing.flags().is_leaf() {
+            // a huge page here would throw away the smaller mappings below it
+            warn!("paging: {:#x} is already mapped with smaller pages", vaddr);
+            return false;
+        }
+        // we never take access or dirty faults, so set both up front
+        let flags = flags | PteFlags::ACCESSED | PteFlags::DIRTY | PteFlags::GLOBAL;
+        unsafe { *entry = Entry::new(paddr, flags) };
+        true
+    }
+
+    /// map `size` bytes at `vaddr` to `paddr`, widened out to whole pages. uses the biggest pages
+    /// that the alignment allows
+    pub fn map(&mut self, vaddr: usize, paddr: usize, size: usize, flags: PteFlags) -> bool {
+        let offset = vaddr % PAGE_SIZE;
+        let mut vaddr = vaddr - offset;
+        let mut paddr = paddr - offset;
+        let end = (vaddr + size + offset + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if end > VA_LIMIT {
+            warn!("paging: {:#x}..{:#x} is out of Sv39 range", vaddr, end);
+            return false;
+        }
+        while vaddr < end {
+            let level = (0..LEVELS)
+                .rev()
+                .find(|&level| {
+                    let size = level_size(level);
+                    vaddr % size == 0 && paddr % size == 0 && vaddr + size <= end
+                })
+                .unwrap();
+            if !self.map_page(vaddr, paddr, level, flags) {
+                return false;
+            }
+            vaddr += level_size(level);
+            paddr += level_size(level);
+        }
+        true
+    }
+
+    /// make the 4KiB page at `vaddr` invalid, first breaking whatever huge page covers it into
+    /// pages one level down until it is a leaf of its own. false if it wasn't mapped
+    fn unmap_page(&mut self, vaddr: usize) -> bool {
+        let mut table = self.root;
+        for level in (1..LEVELS).rev() {
+            let entry = Self::entry(table, vpn(vaddr, level));
+            let existing = unsafe { *entry };
+            if !existing.is_valid() {
+                return false;
+            }
+            table = if existing.flags().is_leaf() {
+                // the same mapping in smaller pages, filled in before the entry is swapped so a
+                // walk on another hart sees either the old huge page or the complete table
+                let Some(next) = Self::new_table() else {
+                    return false;
+                };
+                let flags = existing.flags() - PteFlags::VALID;
+                for index in 0..ENTRIES {
+                    let paddr = existing.address() + index * level_size(level - 1);
+                    unsafe { *Self::entry(next, index) = Entry::new(paddr, flags) };
+                }
+                unsafe { *entry = Entry::new(next, PteFlags::empty()) };
+                next
+            } else {
+                existing.address()
+            };
+        }
+        let entry = Self::entry(table, vpn(vaddr, 0));
+        if !unsafe { *entry }.is_valid() {
+            return false;
+        }
+        unsafe { *entry = Entry(0) };
+        true
+    }
+
+    /// identity map a region
+    pub fn identity_map(&mut self, region: &Region, flags: PteFlags) -> bool {
+        debug!("paging: map {} {:?}", region, flags);
+        self.map(region.start, region.start, region.size(), flags)
+    }
+
+    /// walk the tables for `vaddr`, giving the physical address and the leaf's flags
+    pub fn translate(&self, vaddr: usize) -> Option<(usize, PteFlags)> {
+        let mut table = self.root;
+        for level in (0..LEVELS).rev() {
+            let entry = unsafe { *Self::entry(table, vpn(vaddr, level)) };
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.flags().is_leaf() {
+                let offset = vaddr % level_size(level);
+                return Some((entry.address() + offset, entry.flags()));
+            }
+            table = entry.address();
+        }
+        None
+    }
+}
+
+static KERNEL_SPACE: Once<IrqSafeSpinlock<AddressSpace>> = Once::new();
+/// set once `satp` points at `KERNEL_SPACE`
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// is translation on
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// does the cpu have Svpbmt, which is the only way to mark pages uncached under Sv39
+fn has_svpbmt(fdt: &Fdt) -> bool {
+    fdt.cpus().next().map_or(false, |cpu| {
+        let isa = cpu
+            .property("riscv,isa")
+            .and_then(|prop| prop.as_str())
+            .map_or(false, |isa| isa.split('_').any(|ext| ext == "svpbmt"));
+        let extensions = cpu.property("riscv,isa-extensions").map_or(false, |prop| {
+            prop.value
+                .split(|&byte| byte == 0)
+                .any(|ext| ext == b"svpbmt")
+        });
+        isa || extensions
+    })
+}
+
+/// device register windows: every `reg` outside RAM, plus the pci host bridge's address windows
+fn mmio_regions<'a>(fdt: &'a Fdt) -> impl Iterator<Item = Region> + 'a {
+    let ram = |addr: usize| {
+        fdt.memory().regions().any(|memory| {
+            let start = memory.starting_address as usize;
+            (start..start + memory.size.unwrap_or(0)).contains(&addr)
+        })
+    };
+    let regs = fdt
+        .all_nodes()
+        .filter(|node| !node.name.starts_with("memory") && !node.name.starts_with("cpu"))
+        .filter_map(|node| node.reg())
+        .flatten()
+        .filter_map(|reg| Some((reg.starting_address as usize, reg.size?)))
+        .filter(move |&(start, size)| size > 0 && !ram(start))
+        .map(|(start, size)| Region::new("mmio", start, start + size));
+    // each pci range is 7 cells: flags and bus address, cpu address, size
+    let windows = fdt
+        .find_compatible(&["pci-host-ecam-generic"])
+        .and_then(|pci| pci.property("ranges"))
+        .into_iter()
+        .flat_map(|ranges| ranges.value.chunks_exact(28))
+        .map(|range| {
+            let start = u64::from_be_bytes(range[12..20].try_into().unwrap()) as usize;
+            let size = u64::from_be_bytes(range[20..28].try_into().unwrap()) as usize;
+            Region::new("pci window", start, start + size)
+        });
+    regs.chain(windows)
+}
+
+/// RAM outside of the kernel's own reservation, minus anything firmware asked us not to map
+fn free_ram(fdt: &Fdt) -> Vec<Region> {
+    let mut holes = vec![layout::kernel_reserved()];
+    if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+        for child in reserved_memory
+            .children()
+            .filter(|child| child.property("no-map").is_some())
+        {
+            for reg in child.reg().into_iter().flatten() {
+                let start = reg.starting_address as usize;
+                holes.push(Region::new("no-map", start, start + reg.size.unwrap_or(0)));
+            }
+        }
+    }
+    holes.sort_by_key(|hole| hole.start);
+
+    let mut free = Vec::new();
+    for memory in fdt.memory().regions() {
+        let bank = memory.starting_address as usize;
+        let end = bank + memory.size.unwrap_or(0);
+        let mut start = bank;
+        for hole in holes
+            .iter()
+            .filter(|hole| hole.end > bank && hole.start < end)
+        {
+            if hole.start > start {
+                free.push(Region::new("ram", start, hole.start));
+            }
+            start = start.max(hole.end);
+        }
+        if start < end {
+            free.push(Region::new("ram", start, end));
+        }
+    }
+    free
+}
+
+/// build the kernel's page tables
+fn build(fdt: &Fdt) -> Option<AddressSpace> {
+    let mut space = AddressSpace::new()?;
+    let mut ok = space.identity_map(&layout::kernel_text(), PteFlags::TEXT)
+        && space.identity_map(&layout::kernel_rodata(), PteFlags::RODATA)
+        && space.identity_map(&layout::kernel_data(), PteFlags::DATA);
+    // the guard pages below these are simply never mapped
+    for region in layout::guarded() {
+        ok &= space.identity_map(&region, PteFlags::DATA);
+    }
+    for region in free_ram(fdt) {
+        ok &= space.identity_map(&region, PteFlags::DATA);
+    }
+    let mmio = if has_svpbmt(fdt) {
+        PteFlags::MMIO | PteFlags::PBMT_IO
+    } else {
+        PteFlags::MMIO
+    };
+    for region in mmio_regions(fdt) {
+        ok &= space.identity_map(&region, mmio);
+    }
+    ok.then_some(space)
+}
+
+/// build the kernel's page tables and turn on translation. needs pmm.
+/// in M-mode there is no translation, so this does nothing and returns false
+pub fn init(fdt: &Fdt) -> bool {
+    if csr::mode() == Mode::Machine {
+        info!("paging: running in M-mode, translation stays off");
+        return false;
+    }
+    let Some(space) = build(fdt) else {
+        warn!("paging: unable to build the kernel page tables, translation stays off");
+        return false;
+    };
+    let root = space.root;
+    let space = KERNEL_SPACE.call_once(|| IrqSafeSpinlock::new("kernel page tables", space));
+    activate(&space.lock());
+    ENABLED.store(true, Ordering::Relaxed);
+    info!("paging: Sv39 on, root table at {:#x}", root);
+    true
+}
+
+/// switch this hart over to `space`
+fn activate(space: &AddressSpace) {
+    unsafe { asm!("sfence.vma") };
+    csr_write!("satp", space.satp());
+    unsafe { asm!("sfence.vma") };
+}
+
+/// turn translation on for a hart other than the one that ran `init`
+pub fn init_hart() {
+    if let Some(space) = KERNEL_SPACE.get() {
+        activate(&space.lock());
+    }
+}
+
+/// drop this hart's cached translations, and ask the other harts to do the same. they do it
+/// whenever they get round to the IPI, nobody waits for them
+fn flush_tlbs() {
+    unsafe { asm!("sfence.vma") };
+    smp::broadcast_ipi(IpiMessage::FlushTlb);
+}
+
+/// take the RAM page at `addr` out of the kernel's mapping so that touching it faults, for the
+/// guard pages below task stacks. does nothing while translation is off
+pub fn unmap_guard(addr: usize) {
+    let Some(space) = KERNEL_SPACE.get().filter(|_| enabled()) else {
+        return;
+    };
+    if !space.lock().unmap_page(addr) {
+        warn!("paging: unable to unmap the guard page at {:#x}", addr);
+    }
+    flush_tlbs();
+}
+
+/// put back a page taken out with `unmap_guard`, before it goes back to pmm
+pub fn remap_guard(addr: usize) {
+    let Some(space) = KERNEL_SPACE.get().filter(|_| enabled()) else {
+        return;
+    };
+    // the tables below it were all made by `unmap_guard`, so this can't need a new one
+    let mapped = space.lock().map_page(addr, addr, 0, PteFlags::DATA);
+    assert!(
+        mapped,
+        "paging: unable to remap the guard page at {:#x}",
+        addr
+    );
+    flush_tlbs();
+}
+
+/// the physical address behind `vaddr`, or `vaddr` itself while translation is off
+pub fn virt_to_phys(vaddr: usize) -> Option<usize> {
+    match KERNEL_SPACE.get() {
+        Some(space) if enabled() => space.lock().translate(vaddr).map(|(paddr, _)| paddr),
+        _ => Some(vaddr),
+    }
+}
+
+/// where the kernel can reach `paddr`. everything is identity mapped, so this just checks
+/// that the page really is mapped
+pub fn phys_to_virt(paddr: usize) -> Option<usize> {
+    (virt_to_phys(paddr) == Some(paddr)).then_some(paddr)
+}
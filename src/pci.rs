@@ -0,0 +1,443 @@
+// pci.rs
+// raw configuration space access for the registers `PciRoot` keeps to itself, and a registry of
+// the functions `pci_enum` found with an lspci style dump
+#![allow(dead_code)]
+use crate::{
+    pci_enum::{BridgeWindows, PciFunction, PciTree},
+    print, println,
+    sync::Once,
+};
+use alloc::vec::Vec;
+use core::fmt;
+use virtio_drivers::transport::pci::bus::{
+    BarInfo, CapabilityInfo, Command, DeviceFunction, DeviceFunctionInfo, HeaderType,
+    MemoryBarType, PciRoot, Status,
+};
+
+/// offset of the interrupt line (low byte) and interrupt pin (second byte) registers
+const INTERRUPT_OFFSET: u8 = 0x3c;
+/// the capability id virtio uses for its vendor specific capabilities
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// virtio capability type for the ISR status byte
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+/// first interrupt id the QEMU virt machine wires PCI INTA..INTD to
+const PCIE_IRQ_BASE: u32 = 32;
+
+/// an ECAM window, addressed the same way `PciRoot` addresses it
+#[derive(Copy, Clone, Debug)]
+pub struct ConfigSpace {
+    base: usize,
+}
+
+impl ConfigSpace {
+    /// # Safety
+    /// `base` must be the start of the ECAM region the `PciRoot` was made from
+    pub unsafe fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    fn address(&self, device_function: DeviceFunction, offset: u8) -> usize {
+        let bdf = (device_function.bus as usize) << 8
+            | (device_function.device as usize) << 3
+            | device_function.function as usize;
+        self.base + (bdf << 12) + (offset as usize & !0x3)
+    }
+
+    /// read the 32-bit register containing `offset`
+    pub fn read_u32(&self, device_function: DeviceFunction, offset: u8) -> u32 {
+        unsafe { (self.address(device_function, offset) as *const u32).read_volatile() }
+    }
+
+    /// write the 32-bit register containing `offset`
+    pub fn write_u32(&self, device_function: DeviceFunction, offset: u8, value: u32) {
+        unsafe { (self.address(device_function, offset) as *mut u32).write_volatile(value) }
+    }
+
+    /// read a single byte of configuration space
+    pub fn read_u8(&self, device_function: DeviceFunction, offset: u8) -> u8 {
+        (self.read_u32(device_function, offset) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    /// the interrupt pin the function uses, 1..=4 for INTA..INTD or 0 if it has none
+    pub fn interrupt_pin(&self, device_function: DeviceFunction) -> u8 {
+        self.read_u8(device_function, INTERRUPT_OFFSET + 1)
+    }
+
+    /// find the address of the virtio ISR status byte, reading it acknowledges the interrupt.
+    /// the BAR is looked up in what `init` recorded rather than sized again, and `None` if it's
+    /// an I/O BAR or wasn't given an address
+    pub fn virtio_isr_address(
+        &self,
+        root: &PciRoot,
+        device_function: DeviceFunction,
+    ) -> Option<usize> {
+        let capability = root.capabilities(device_function).find(|cap| {
+            cap.id == PCI_CAP_ID_VNDR
+                && self.read_u8(device_function, cap.offset + 3) == VIRTIO_PCI_CAP_ISR_CFG
+        })?;
+        let bar = self.read_u8(device_function, capability.offset + 4);
+        let offset = self.read_u32(device_function, capability.offset + 8);
+        let bar = find(device_function)?
+            .bars
+            .iter()
+            .find(|recorded| recorded.index == bar)?;
+        match bar.info {
+            BarInfo::Memory { .. } => Some(bar.cpu_address? as usize + offset as usize),
+            BarInfo::IO { .. } => None,
+        }
+    }
+}
+
+/// the PLIC interrupt id for INTx pin `pin` (1..=4) arriving from root bus slot `slot`, see
+/// `PciFunction::intx` for getting those from behind bridges.
+/// QEMU virt rotates the pins by slot number across 4 lines starting at 32
+pub fn intx_irq(slot: u8, pin: u8) -> u32 {
+    PCIE_IRQ_BASE + (slot as u32 + pin as u32 - 1) % 4
+}
+
+/// every function found by `pci_enum`, filled in once by `init`
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+/// how many BARs a header of this type has, type 1 (bridges) only has 2
+pub fn bar_count(header_type: HeaderType) -> u8 {
+    match header_type {
+        HeaderType::Standard => 6,
+        HeaderType::PciPciBridge => 2,
+        _ => 0,
+    }
+}
+
+/// one assigned BAR
+#[derive(Clone, Debug)]
+pub struct Bar {
+    pub index: u8,
+    pub info: BarInfo,
+    /// where the CPU reaches it, `None` if it's outside every window
+    pub cpu_address: Option<u64>,
+}
+
+/// bus numbers and windows of a PCI-to-PCI bridge
+#[derive(Clone, Debug)]
+pub struct BridgeInfo {
+    pub primary: u8,
+    pub secondary: u8,
+    pub subordinate: u8,
+    pub windows: BridgeWindows,
+}
+
+/// what we know about one function
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub device_function: DeviceFunction,
+    pub info: DeviceFunctionInfo,
+    /// its BARs were placed and decoding turned on
+    pub enabled: bool,
+    pub status: Status,
+    pub command: Command,
+    pub bars: Vec<Bar>,
+    pub capabilities: Vec<CapabilityInfo>,
+    /// 1..=4 for INTA..INTD, 0 for none
+    pub interrupt_pin: u8,
+    pub irq: Option<u32>,
+    pub bridge: Option<BridgeInfo>,
+}
+
+impl PciDevice {
+    fn read(
+        root: &PciRoot,
+        config: &ConfigSpace,
+        function: &PciFunction,
+        cpu_address: &impl Fn(&BarInfo) -> Option<u64>,
+    ) -> Self {
+        let device_function = function.device_function;
+        // from the walk, sizing them again would make a live device decode garbage for a moment
+        let bars = function
+            .bars
+            .iter()
+            .map(|(index, info)| Bar {
+                index: *index,
+                cpu_address: cpu_address(info),
+                info: info.clone(),
+            })
+            .collect();
+        let (status, command) = root.get_status_command(device_function);
+        Self {
+            device_function,
+            info: function.info.clone(),
+            enabled: function.enabled,
+            status,
+            command,
+            bars,
+            capabilities: root.capabilities(device_function).collect(),
+            interrupt_pin: config.interrupt_pin(device_function),
+            irq: function.intx.map(|(slot, pin)| intx_irq(slot, pin)),
+            bridge: function.bridge.as_ref().map(|bridge| BridgeInfo {
+                primary: device_function.bus,
+                secondary: bridge.secondary,
+                subordinate: bridge.subordinate,
+                windows: bridge.windows.clone(),
+            }),
+        }
+    }
+
+    /// `Some` if it has a capability with this id
+    pub fn capability(&self, id: u8) -> Option<&CapabilityInfo> {
+        self.capabilities
+            .iter()
+            .find(|capability| capability.id == id)
+    }
+}
+
+/// record every function in `tree`, `cpu_address` translates a BAR's bus address for the CPU
+pub fn init(
+    root: &PciRoot,
+    config: &ConfigSpace,
+    tree: &PciTree,
+    cpu_address: impl Fn(&BarInfo) -> Option<u64>,
+) {
+    DEVICES.call_once(|| {
+        tree.functions()
+            .into_iter()
+            .map(|function| PciDevice::read(root, config, function, &cpu_address))
+            .collect()
+    });
+}
+
+/// every recorded function in bus order, empty before `init`
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn find(device_function: DeviceFunction) -> Option<&'static PciDevice> {
+    devices()
+        .iter()
+        .find(|device| device.device_function == device_function)
+}
+
+/// functions of a class, and of a subclass too if given
+pub fn by_class(class: u8, subclass: Option<u8>) -> impl Iterator<Item = &'static PciDevice> {
+    devices().iter().filter(move |device| {
+        device.info.class == class
+            && subclass.map_or(true, |subclass| device.info.subclass == subclass)
+    })
+}
+
+pub fn by_id(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices().iter().filter(move |device| {
+        device.info.vendor_id == vendor_id && device.info.device_id == device_id
+    })
+}
+
+/// the class name lspci would show, for the classes we're likely to see
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, _) => "Display controller",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, _) => "Serial bus controller",
+        (0x00, _) => "Unclassified device",
+        (0xff, _) => "Unassigned class",
+        _ => "Device",
+    }
+}
+
+fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x05 => "MSI",
+        PCI_CAP_ID_VNDR => "Vendor Specific Information",
+        0x0d => "Subsystem",
+        0x10 => "Express",
+        0x11 => "MSI-X",
+        _ => "Unknown",
+    }
+}
+
+/// `+` or `-` like lspci does for flags
+fn sign(set: bool) -> char {
+    if set {
+        '+'
+    } else {
+        '-'
+    }
+}
+
+/// print an lspci style size, 4K, 1M and so on
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            size if size >= 1 << 30 && size % (1 << 30) == 0 => write!(f, "{}G", size >> 30),
+            size if size >= 1 << 20 && size % (1 << 20) == 0 => write!(f, "{}M", size >> 20),
+            size if size >= 1 << 10 && size % (1 << 10) == 0 => write!(f, "{}K", size >> 10),
+            size => write!(f, "{}", size),
+        }
+    }
+}
+
+/// the one line `lspci` summary
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = &self.info;
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x} (rev {:02x})",
+            self.device_function,
+            class_name(info.class, info.subclass),
+            info.class,
+            info.subclass,
+            info.vendor_id,
+            info.device_id,
+            info.revision
+        )?;
+        if info.prog_if != 0 {
+            write!(f, " (prog-if {:02x})", info.prog_if)?;
+        }
+        Ok(())
+    }
+}
+
+/// the `lspci -vv` view of a function
+pub struct Verbose<'a>(pub &'a PciDevice);
+
+impl fmt::Display for Verbose<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let device = self.0;
+        writeln!(f, "{}", device)?;
+        let command = device.command;
+        writeln!(
+            f,
+            "\tControl: I/O{} Mem{} BusMaster{} DisINTx{}",
+            sign(command.contains(Command::IO_SPACE)),
+            sign(command.contains(Command::MEMORY_SPACE)),
+            sign(command.contains(Command::BUS_MASTER)),
+            sign(command.contains(Command::INTERRUPT_DISABLE)),
+        )?;
+        let status = device.status;
+        writeln!(
+            f,
+            "\tStatus: Cap{} INTx{} >TAbort{} <TAbort{} <MAbort{} >SERR{} <PERR{}",
+            sign(status.contains(Status::CAPABILITIES_LIST)),
+            sign(status.contains(Status::INTERRUPT_STATUS)),
+            sign(status.contains(Status::SIGNALED_TARGET_ABORT)),
+            sign(status.contains(Status::RECEIVED_TARGET_ABORT)),
+            sign(status.contains(Status::RECEIVED_MASTER_ABORT)),
+            sign(status.contains(Status::SIGNALED_SYSTEM_ERROR)),
+            sign(status.contains(Status::DETECTED_PARITY_ERROR)),
+        )?;
+        if device.interrupt_pin != 0 {
+            match device.interrupt_pin {
+                pin @ 1..=4 => write!(f, "\tInterrupt: pin {}", (b'A' + pin - 1) as char)?,
+                pin => write!(f, "\tInterrupt: pin {:#04x}?", pin)?,
+            }
+            match device.irq {
+                Some(irq) => writeln!(f, " routed to IRQ {}", irq)?,
+                None => writeln!(f)?,
+            }
+        }
+        if let Some(bridge) = &device.bridge {
+            writeln!(
+                f,
+                "\tBus: primary={:02x}, secondary={:02x}, subordinate={:02x}",
+                bridge.primary, bridge.secondary, bridge.subordinate
+            )?;
+            let windows = [
+                ("I/O", &bridge.windows.io),
+                ("Memory", &bridge.windows.memory),
+                ("Prefetchable memory", &bridge.windows.prefetchable),
+            ];
+            for (name, window) in windows {
+                match window {
+                    Some(window) => writeln!(
+                        f,
+                        "\t{} behind bridge: {:08x}-{:08x} [size={}]",
+                        name,
+                        window.start,
+                        window.end - 1,
+                        Size(window.end - window.start)
+                    )?,
+                    None => writeln!(f, "\t{} behind bridge: [disabled]", name)?,
+                }
+            }
+        }
+        for bar in &device.bars {
+            match bar.info {
+                BarInfo::Memory {
+                    address_type,
+                    prefetchable,
+                    address,
+                    size,
+                } => write!(
+                    f,
+                    "\tRegion {}: Memory at {:x} ({}, {}prefetchable) [size={}]",
+                    bar.index,
+                    address,
+                    match address_type {
+                        MemoryBarType::Width32 => "32-bit",
+                        MemoryBarType::Below1MiB => "low-1M",
+                        MemoryBarType::Width64 => "64-bit",
+                    },
+                    if prefetchable { "" } else { "non-" },
+                    Size(size.into())
+                )?,
+                BarInfo::IO { address, size } => write!(
+                    f,
+                    "\tRegion {}: I/O ports at {:x} [size={}]",
+                    bar.index,
+                    address,
+                    Size(size.into())
+                )?,
+            }
+            let bus_address = match bar.info {
+                BarInfo::Memory { address, .. } => address,
+                BarInfo::IO { address, .. } => address.into(),
+            };
+            // the CPU address only when it isn't the same as the bus address
+            match bar.cpu_address {
+                Some(cpu_address) if cpu_address != bus_address => {
+                    writeln!(f, " at {:#x}", cpu_address)?
+                }
+                Some(_) => writeln!(f)?,
+                None => writeln!(f, " [outside windows]")?,
+            }
+        }
+        for capability in &device.capabilities {
+            writeln!(
+                f,
+                "\tCapabilities: [{:02x}] {}",
+                capability.offset,
+                capability_name(capability.id)
+            )?;
+        }
+        if !device.enabled {
+            writeln!(f, "\tDisabled: BARs not placed")?;
+        }
+        Ok(())
+    }
+}
+
+/// print every recorded function, `verbose` for the `lspci -vv` view
+pub fn print_devices(verbose: bool) {
+    for device in devices() {
+        if verbose {
+            print!("{}", Verbose(device));
+        } else {
+            println!("{}", device);
+        }
+    }
+}
@@ -0,0 +1,595 @@
+// pci_enum.rs
+// walks the PCI hierarchy from the host bridge's first bus: numbers the buses behind PCI-to-PCI
+// bridges, places every BAR, and opens bridge windows around whatever ended up behind them
+#![allow(dead_code)]
+use crate::{
+    bar32alloc::{BusWindow, PciAllocError, PciMemory32Allocator},
+    bar64alloc::PciMemory64Allocator,
+    barioalloc::PciIoAllocator,
+    pci::{bar_count, ConfigSpace},
+    println,
+    sync::Once,
+};
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+use fdt::node::FdtNode;
+use log::*;
+use virtio_drivers::transport::pci::bus::{
+    BarInfo, Command, DeviceFunction, DeviceFunctionInfo, HeaderType, MemoryBarType, PciRoot,
+};
+
+/// primary, secondary and subordinate bus numbers, and the secondary latency timer
+const BUS_NUMBERS_OFFSET: u8 = 0x18;
+/// I/O base and limit in the low half, secondary status in the high half
+const IO_BASE_LIMIT_OFFSET: u8 = 0x1c;
+const MEMORY_BASE_LIMIT_OFFSET: u8 = 0x20;
+const PREFETCH_BASE_LIMIT_OFFSET: u8 = 0x24;
+const PREFETCH_BASE_UPPER_OFFSET: u8 = 0x28;
+const PREFETCH_LIMIT_UPPER_OFFSET: u8 = 0x2c;
+const IO_UPPER_OFFSET: u8 = 0x30;
+
+/// bridge windows are decoded in these units
+const MEMORY_GRANULARITY: u64 = 1 << 20;
+const IO_GRANULARITY: u64 = 1 << 12;
+
+/// the host bridge's memory windows, kept for `memory_cpu_address` once enumeration is done
+static MEMORY_WINDOWS: Once<Vec<BusWindow>> = Once::new();
+
+/// the CPU physical address of the memory bus address `bus_address`, the address a memory BAR
+/// holds. `None` if it's outside the host bridge's windows or before `enumerate`
+pub fn memory_cpu_address(bus_address: u64) -> Option<u64> {
+    MEMORY_WINDOWS
+        .get()?
+        .iter()
+        .find(|window| window.contains(bus_address))
+        .map(|window| window.cpu_address(bus_address))
+}
+
+/// the address allocators for every kind of BAR
+pub struct Allocators {
+    pub memory32: PciMemory32Allocator,
+    /// `None` when the host bridge has no 64-bit window
+    pub memory64: Option<PciMemory64Allocator>,
+    /// `None` when the host bridge has no I/O window
+    pub io: Option<PciIoAllocator>,
+    /// the 64-bit window everything behind the root bus bridge being walked goes in, once
+    /// something has gone in one
+    memory64_window: Option<usize>,
+}
+
+impl Allocators {
+    pub fn for_pci_ranges(pci_node: &FdtNode) -> Result<Self, PciAllocError> {
+        Ok(Self {
+            memory32: PciMemory32Allocator::for_pci_ranges(pci_node)?,
+            memory64: PciMemory64Allocator::for_pci_ranges(pci_node).ok(),
+            io: PciIoAllocator::for_pci_ranges(pci_node).ok(),
+            memory64_window: None,
+        })
+    }
+
+    /// place the first `bars` BARs of a function, returns the decode bits it needs turned on.
+    /// behind a bridge non-prefetchable BARs have to go in 32-bit space, since that's all the
+    /// bridge's memory window can cover. every implemented BAR goes in `placed` with its index,
+    /// holding the address it was given, so nothing has to size it again once it's live
+    pub fn allocate_bars(
+        &mut self,
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+        bars: u8,
+        behind_bridge: bool,
+        placed: &mut Vec<(u8, BarInfo)>,
+    ) -> Result<Command, PciAllocError> {
+        let mut command = Command::MEMORY_SPACE | Command::BUS_MASTER;
+        let mut bar_index = 0;
+        while bar_index < bars {
+            let info = root.bar_info(device_function, bar_index).unwrap();
+            debug!("BAR {}: {}", bar_index, info);
+            let assigned = match info {
+                BarInfo::IO { size, .. } if size > 0 => match &mut self.io {
+                    Some(io) => {
+                        let port = io.allocate_io(size)?;
+                        debug!("Allocated ports {:#x} at {:#x}", port, io.cpu_address(port));
+                        root.set_bar_32(device_function, bar_index, port);
+                        command |= Command::IO_SPACE;
+                        Some(BarInfo::IO {
+                            address: port,
+                            size,
+                        })
+                    }
+                    None => {
+                        warn!("BAR {}: no I/O window to put it in", bar_index);
+                        Some(info.clone())
+                    }
+                },
+                BarInfo::Memory {
+                    address_type: MemoryBarType::Width32,
+                    prefetchable,
+                    size,
+                    ..
+                } if size > 0 => {
+                    let address = self.memory32.allocate_memory_32(size)?;
+                    debug!(
+                        "Allocated address {:#010x} at {:#x}",
+                        address,
+                        self.memory32.cpu_address(address)
+                    );
+                    root.set_bar_32(device_function, bar_index, address);
+                    Some(BarInfo::Memory {
+                        address_type: MemoryBarType::Width32,
+                        prefetchable,
+                        address: address.into(),
+                        size,
+                    })
+                }
+                BarInfo::Memory {
+                    address_type: MemoryBarType::Width64,
+                    prefetchable,
+                    size,
+                    ..
+                } if size > 0 => {
+                    let memory64 = self
+                        .memory64
+                        .as_mut()
+                        .filter(|_| prefetchable || !behind_bridge);
+                    let window = &mut self.memory64_window;
+                    let address = match memory64.map(|memory64| {
+                        if behind_bridge {
+                            memory64.allocate_memory_64_in(window, size.into(), prefetchable)
+                        } else {
+                            memory64.allocate_memory_64(size.into(), prefetchable)
+                        }
+                    }) {
+                        Some(Err(PciAllocError::NoWindow)) | None => {
+                            // no 64-bit window for it, a 64-bit BAR works below 4 GiB just as well
+                            self.memory32.allocate_memory_32(size)?.into()
+                        }
+                        Some(address) => address?,
+                    };
+                    let cpu_address = self
+                        .memory64
+                        .as_ref()
+                        .and_then(|memory64| memory64.cpu_address(address))
+                        .unwrap_or_else(|| self.memory32.cpu_address(address as u32));
+                    debug!("Allocated address {:#018x} at {:#x}", address, cpu_address);
+                    root.set_bar_64(device_function, bar_index, address);
+                    Some(BarInfo::Memory {
+                        address_type: MemoryBarType::Width64,
+                        prefetchable,
+                        address,
+                        size,
+                    })
+                }
+                BarInfo::Memory {
+                    address_type: MemoryBarType::Below1MiB,
+                    size,
+                    ..
+                } => {
+                    warn!("BAR {}: can't place a below 1MiB BAR", bar_index);
+                    (size > 0).then(|| info.clone())
+                }
+                _ => None,
+            };
+            if let Some(assigned) = assigned {
+                placed.push((bar_index, assigned));
+            }
+            bar_index += 1;
+            if info.takes_two_entries() {
+                bar_index += 1;
+            }
+        }
+        Ok(command)
+    }
+
+    /// the CPU physical address of a BAR placed by `allocate_bars`, `None` if it's outside every
+    /// window
+    pub fn cpu_address(&self, bar: &BarInfo) -> Option<u64> {
+        match *bar {
+            BarInfo::IO { address, .. } => self.io.as_ref().map(|io| io.cpu_address(address)),
+            BarInfo::Memory { address, .. } => self
+                .memory64
+                .as_ref()
+                .and_then(|memory64| memory64.cpu_address(address))
+                .or_else(|| {
+                    let address = u32::try_from(address).ok()?;
+                    self.memory32
+                        .contains(address)
+                        .then(|| self.memory32.cpu_address(address))
+                }),
+        }
+    }
+
+    /// every memory window, 32-bit and 64-bit
+    fn memory_windows(&self) -> Vec<BusWindow> {
+        let mut windows = Vec::from([*self.memory32.window()]);
+        if let Some(memory64) = &self.memory64 {
+            windows.extend(memory64.windows().copied());
+        }
+        windows
+    }
+
+    /// where each window's next free address is, and line them up for a bridge window to start
+    fn mark(&mut self) -> Marks {
+        self.memory32.window_mut().align_next(MEMORY_GRANULARITY);
+        let memory64 = self.memory64.as_mut().map_or(Vec::new(), |memory64| {
+            memory64
+                .windows_mut()
+                .map(|window| {
+                    window.align_next(MEMORY_GRANULARITY);
+                    window.next()
+                })
+                .collect()
+        });
+        let io = self.io.as_mut().map(|io| {
+            io.window_mut().align_next(IO_GRANULARITY);
+            io.window_mut().next()
+        });
+        Marks {
+            memory32: self.memory32.window_mut().next(),
+            memory64,
+            io,
+        }
+    }
+
+    /// the bridge windows covering everything allocated since `before`
+    fn windows_since(&mut self, before: &Marks) -> BridgeWindows {
+        let after = self.mark();
+        let used = |before: u64, after: u64| (after > before).then_some(before..after);
+        // everything that went in 64-bit space behind a bridge is prefetchable and came out of
+        // the one window `memory64_window` kept it to, so at most one of them moved
+        let prefetchable = before
+            .memory64
+            .iter()
+            .zip(&after.memory64)
+            .find_map(|(&before, &after)| used(before, after));
+        BridgeWindows {
+            memory: used(before.memory32, after.memory32),
+            prefetchable,
+            io: before.io.zip(after.io).and_then(|(b, a)| used(b, a)),
+        }
+    }
+}
+
+/// next free addresses, see `Allocators::mark`
+struct Marks {
+    memory32: u64,
+    memory64: Vec<u64>,
+    io: Option<u64>,
+}
+
+/// bus address ranges forwarded by a bridge
+#[derive(Clone, Debug, Default)]
+pub struct BridgeWindows {
+    pub memory: Option<Range<u64>>,
+    pub prefetchable: Option<Range<u64>>,
+    pub io: Option<Range<u64>>,
+}
+
+/// a PCI-to-PCI bridge and what's behind it
+#[derive(Clone, Debug)]
+pub struct Bridge {
+    pub secondary: u8,
+    pub subordinate: u8,
+    pub windows: BridgeWindows,
+    pub children: Vec<PciFunction>,
+}
+
+/// one function found by the walk
+#[derive(Clone, Debug)]
+pub struct PciFunction {
+    pub device_function: DeviceFunction,
+    pub info: DeviceFunctionInfo,
+    /// its BARs were placed and decoding turned on, drivers should leave it alone otherwise
+    pub enabled: bool,
+    /// index and contents of every implemented BAR, as `Allocators::allocate_bars` left it
+    pub bars: Vec<(u8, BarInfo)>,
+    /// the root bus slot and pin its INTx line arrives on, after every bridge on the way has
+    /// swizzled it. `None` if it has no interrupt pin
+    pub intx: Option<(u8, u8)>,
+    /// `Some` for PCI-to-PCI bridges
+    pub bridge: Option<Bridge>,
+}
+
+/// everything below the host bridge
+#[derive(Clone, Debug)]
+pub struct PciTree {
+    pub buses: Range<u16>,
+    pub functions: Vec<PciFunction>,
+}
+
+impl PciTree {
+    /// every function, depth first, bridges before what's behind them
+    pub fn functions(&self) -> Vec<&PciFunction> {
+        fn collect<'a>(functions: &'a [PciFunction], out: &mut Vec<&'a PciFunction>) {
+            for function in functions {
+                out.push(function);
+                if let Some(bridge) = &function.bridge {
+                    collect(&bridge.children, out);
+                }
+            }
+        }
+        let mut out = Vec::new();
+        collect(&self.functions, &mut out);
+        out
+    }
+
+    /// print the hierarchy, indented by depth
+    pub fn print(&self) {
+        fn print_level(functions: &[PciFunction], depth: usize) {
+            for function in functions {
+                println!(
+                    "{:indent$}{} {:04x}:{:04x} class {:02x}{:02x}",
+                    "",
+                    function.device_function,
+                    function.info.vendor_id,
+                    function.info.device_id,
+                    function.info.class,
+                    function.info.subclass,
+                    indent = depth * 2
+                );
+                if let Some(bridge) = &function.bridge {
+                    println!(
+                        "{:indent$}  bus {:02x}..={:02x} {}",
+                        "",
+                        bridge.secondary,
+                        bridge.subordinate,
+                        bridge.windows,
+                        indent = depth * 2
+                    );
+                    print_level(&bridge.children, depth + 1);
+                }
+            }
+        }
+        print_level(&self.functions, 0);
+    }
+}
+
+impl fmt::Display for BridgeWindows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let windows = [
+            ("mem", &self.memory),
+            ("pref", &self.prefetchable),
+            ("io", &self.io),
+        ];
+        for (name, window) in windows {
+            match window {
+                Some(window) => write!(f, "{} {:#x}..{:#x} ", name, window.start, window.end)?,
+                None => write!(f, "{} off ", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the `bus-range` property, all 256 buses if it's missing
+pub fn bus_range(pci_node: &FdtNode) -> Range<u16> {
+    let range = pci_node.property("bus-range").and_then(|prop| {
+        let cell = |i: usize| {
+            Some(u32::from_be_bytes(
+                prop.value.get(i * 4..i * 4 + 4)?.try_into().ok()?,
+            ))
+        };
+        Some((cell(0)?, cell(1)?))
+    });
+    match range {
+        Some((first, last)) if first <= last && last <= 0xff => first as u16..last as u16 + 1,
+        _ => 0..256,
+    }
+}
+
+/// follow an INTx pin up to the root bus: each bridge turns pin `p` of the device in slot `d`
+/// behind it into pin `(p - 1 + d) % 4 + 1` of its own. returns the root bus slot and pin
+fn route_intx(path: &[u8], device: u8, pin: u8) -> Option<(u8, u8)> {
+    if !(1..=4).contains(&pin) {
+        return None;
+    }
+    let (mut slot, mut pin) = (device, pin);
+    for &bridge in path.iter().rev() {
+        pin = (pin - 1 + slot) % 4 + 1;
+        slot = bridge;
+    }
+    Some((slot, pin))
+}
+
+struct Walk<'a> {
+    root: &'a mut PciRoot,
+    config: &'a ConfigSpace,
+    allocators: &'a mut Allocators,
+    /// the next bus number to give a bridge
+    next_bus: u16,
+    buses: Range<u16>,
+}
+
+impl Walk<'_> {
+    /// `path` is the device number of every bridge between the root bus and `bus`
+    fn bus(&mut self, bus: u8, path: &[u8]) -> Vec<PciFunction> {
+        let behind_bridge = !path.is_empty();
+        let found: Vec<_> = self.root.enumerate_bus(bus).collect();
+        let mut functions = Vec::new();
+        for (device_function, info) in found {
+            let mut bars = Vec::new();
+            let (enabled, bridge) = match info.header_type {
+                HeaderType::PciPciBridge => {
+                    let bridge = self.bridge(device_function, path, &mut bars);
+                    (bridge.is_some(), bridge)
+                }
+                HeaderType::Standard => {
+                    match self.allocators.allocate_bars(
+                        self.root,
+                        device_function,
+                        bar_count(HeaderType::Standard),
+                        behind_bridge,
+                        &mut bars,
+                    ) {
+                        Ok(command) => {
+                            self.root.set_command(device_function, command);
+                            (true, None)
+                        }
+                        Err(err) => {
+                            warn!("pci {}: can't place BARs: {}", device_function, err);
+                            (false, None)
+                        }
+                    }
+                }
+                header_type => {
+                    warn!("pci {}: skipping {:?}", device_function, header_type);
+                    (false, None)
+                }
+            };
+            let pin = self.config.interrupt_pin(device_function);
+            functions.push(PciFunction {
+                device_function,
+                info,
+                enabled,
+                bars,
+                intx: route_intx(path, device_function.device, pin),
+                bridge,
+            });
+        }
+        functions
+    }
+
+    /// `path` leads to the bridge's primary bus, as for `bus`. its own BARs go in `bars`
+    fn bridge(
+        &mut self,
+        device_function: DeviceFunction,
+        path: &[u8],
+        bars: &mut Vec<(u8, BarInfo)>,
+    ) -> Option<Bridge> {
+        let behind_bridge = !path.is_empty();
+        let mut command = match self.allocators.allocate_bars(
+            self.root,
+            device_function,
+            bar_count(HeaderType::PciPciBridge),
+            behind_bridge,
+            bars,
+        ) {
+            Ok(command) => command,
+            Err(err) => {
+                warn!("pci {}: can't place BARs: {}", device_function, err);
+                Command::MEMORY_SPACE | Command::BUS_MASTER
+            }
+        };
+        if !self.buses.contains(&self.next_bus) {
+            warn!("pci {}: out of bus numbers", device_function);
+            return None;
+        }
+        let secondary = self.next_bus as u8;
+        self.next_bus += 1;
+        // forward everything up to the last bus for now, so the walk below can reach it
+        self.set_bus_numbers(device_function, secondary, (self.buses.end - 1) as u8);
+        let before = self.allocators.mark();
+        let mut inner = path.to_vec();
+        inner.push(device_function.device);
+        let children = self.bus(secondary, &inner);
+        let windows = self.allocators.windows_since(&before);
+        if !behind_bridge {
+            // the next bridge on the root bus can start over in any 64-bit window
+            self.allocators.memory64_window = None;
+        }
+        let subordinate = (self.next_bus - 1) as u8;
+        self.set_bus_numbers(device_function, secondary, subordinate);
+        self.set_windows(device_function, &windows);
+        if windows.io.is_some() {
+            command |= Command::IO_SPACE;
+        }
+        self.root.set_command(device_function, command);
+        debug!(
+            "pci {}: bridge to bus {:02x}..={:02x}, {}",
+            device_function, secondary, subordinate, windows
+        );
+        Some(Bridge {
+            secondary,
+            subordinate,
+            windows,
+            children,
+        })
+    }
+
+    fn set_bus_numbers(&self, device_function: DeviceFunction, secondary: u8, subordinate: u8) {
+        let old = self.config.read_u32(device_function, BUS_NUMBERS_OFFSET);
+        let value = (old & 0xff00_0000)
+            | (subordinate as u32) << 16
+            | (secondary as u32) << 8
+            | device_function.bus as u32;
+        self.config
+            .write_u32(device_function, BUS_NUMBERS_OFFSET, value);
+    }
+
+    /// program the windows, a window with its base above its limit is closed
+    fn set_windows(&self, device_function: DeviceFunction, windows: &BridgeWindows) {
+        let config = self.config;
+        let (memory_base, memory_limit) = windows.memory.as_ref().map_or((0xfff0, 0), |memory| {
+            (
+                (memory.start >> 16) as u32 & 0xfff0,
+                ((memory.end - 1) >> 16) as u32 & 0xfff0,
+            )
+        });
+        config.write_u32(
+            device_function,
+            MEMORY_BASE_LIMIT_OFFSET,
+            memory_limit << 16 | memory_base,
+        );
+
+        let (prefetch_base, prefetch_limit) = windows
+            .prefetchable
+            .as_ref()
+            .map_or((0xffff_ffff_fff0_0000, 0), |prefetch| {
+                (prefetch.start, prefetch.end - 1)
+            });
+        config.write_u32(
+            device_function,
+            PREFETCH_BASE_LIMIT_OFFSET,
+            ((prefetch_limit >> 16) as u32 & 0xfff0) << 16
+                | ((prefetch_base >> 16) as u32 & 0xfff0),
+        );
+        config.write_u32(
+            device_function,
+            PREFETCH_BASE_UPPER_OFFSET,
+            (prefetch_base >> 32) as u32,
+        );
+        config.write_u32(
+            device_function,
+            PREFETCH_LIMIT_UPPER_OFFSET,
+            (prefetch_limit >> 32) as u32,
+        );
+
+        let (io_base, io_limit) = windows
+            .io
+            .as_ref()
+            .map_or((0xf000, 0), |io| (io.start, io.end - 1));
+        // the high half is the secondary status, its bits are cleared by writing ones
+        config.write_u32(
+            device_function,
+            IO_BASE_LIMIT_OFFSET,
+            ((io_limit >> 8) as u32 & 0xf0) << 8 | ((io_base >> 8) as u32 & 0xf0),
+        );
+        config.write_u32(
+            device_function,
+            IO_UPPER_OFFSET,
+            ((io_limit >> 16) as u32) << 16 | (io_base >> 16) as u32 & 0xffff,
+        );
+    }
+}
+
+/// walk everything below the host bridge, numbering buses from the start of `buses`
+pub fn enumerate(
+    root: &mut PciRoot,
+    config: &ConfigSpace,
+    allocators: &mut Allocators,
+    buses: Range<u16>,
+) -> PciTree {
+    MEMORY_WINDOWS.call_once(|| allocators.memory_windows());
+    let first = buses.start as u8;
+    let mut walk = Walk {
+        root,
+        config,
+        allocators,
+        next_bus: buses.start + 1,
+        buses: buses.clone(),
+    };
+    let functions = walk.bus(first, &[]);
+    PciTree {
+        buses: buses.start..walk.next_bus,
+        functions,
+    }
+}
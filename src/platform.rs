@@ -0,0 +1,212 @@
+// platform.rs
+// the services the kernel needs from below it, SBI calls under a firmware and the hardware itself in M-mode
+#![allow(dead_code)]
+use crate::{
+    clint,
+    csr::{self, Mode},
+    csr_clear, println, sbi,
+};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use fdt::{node::FdtNode, Fdt};
+use log::*;
+use spin::Once;
+
+/// supervisor software interrupt pending bit in `sip`
+const SIP_SSIP: usize = 1 << 1;
+
+/// where `print!` goes
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Console {
+    /// the 16550 from `/chosen/stdout-path`, driven directly
+    Uart,
+    /// the SBI debug console extension
+    SbiDebug,
+    /// the legacy SBI putchar/getchar calls
+    SbiLegacy,
+}
+
+static CONSOLE: AtomicU8 = AtomicU8::new(Console::Uart as u8);
+static HAS_IPI: AtomicBool = AtomicBool::new(false);
+static HAS_HSM: AtomicBool = AtomicBool::new(false);
+static HAS_SRST: AtomicBool = AtomicBool::new(false);
+
+/// a register write that powers off or resets the machine, from a `syscon-poweroff`/`syscon-reboot` node
+#[derive(Copy, Clone, Debug)]
+struct SysconWrite {
+    addr: usize,
+    value: u32,
+    mask: u32,
+}
+
+impl SysconWrite {
+    fn from_node(fdt: &Fdt, node: FdtNode) -> Option<Self> {
+        let prop = |name| {
+            node.property(name)
+                .and_then(|prop| prop.as_usize())
+                .map(|value| value as u32)
+        };
+        let regmap = fdt.find_phandle(prop("regmap")?)?;
+        let base = regmap.reg()?.next()?.starting_address as usize;
+        let mask = prop("mask").unwrap_or(u32::MAX);
+        Some(Self {
+            addr: base + prop("offset")? as usize,
+            value: prop("value").unwrap_or(mask),
+            mask,
+        })
+    }
+
+    fn write(&self) {
+        let reg = self.addr as *mut u32;
+        unsafe {
+            let old = if self.mask == u32::MAX {
+                0
+            } else {
+                reg.read_volatile()
+            };
+            reg.write_volatile((old & !self.mask) | (self.value & self.mask));
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Syscon {
+    poweroff: Option<SysconWrite>,
+    reboot: Option<SysconWrite>,
+}
+
+static SYSCON: Once<Syscon> = Once::new();
+
+/// where `print!` currently goes
+pub fn console() -> Console {
+    match CONSOLE.load(Ordering::Relaxed) {
+        1 => Console::SbiDebug,
+        2 => Console::SbiLegacy,
+        _ => Console::Uart,
+    }
+}
+
+/// is there a firmware below us to make SBI calls to
+pub fn has_sbi() -> bool {
+    csr::mode() == Mode::Supervisor
+}
+
+/// probe the firmware in S-mode, or find the CLINT and syscon in M-mode. needs `csr::detect_mode`
+pub fn init(fdt: &Fdt) {
+    let mode = csr::mode();
+    if cfg!(feature = "mmode") != (mode == Mode::Machine) {
+        warn!(
+            "platform: built for {}, but booted in {:?}",
+            if cfg!(feature = "mmode") {
+                "M-mode"
+            } else {
+                "S-mode"
+            },
+            mode
+        );
+    }
+    match mode {
+        Mode::Supervisor => {
+            let (major, minor) = sbi::spec_version();
+            info!(
+                "platform: S-mode, SBI {}.{} implementation {} version {:#x}",
+                major,
+                minor,
+                sbi::impl_id(),
+                sbi::impl_version()
+            );
+            HAS_IPI.store(sbi::probe_extension(sbi::EXT_IPI), Ordering::Relaxed);
+            HAS_HSM.store(sbi::probe_extension(sbi::EXT_HSM), Ordering::Relaxed);
+            HAS_SRST.store(sbi::probe_extension(sbi::EXT_SRST), Ordering::Relaxed);
+            let console = if sbi::probe_extension(sbi::EXT_DBCN) {
+                Console::SbiDebug
+            } else if sbi::probe_extension(sbi::EXT_LEGACY_PUTCHAR) {
+                Console::SbiLegacy
+            } else {
+                Console::Uart
+            };
+            CONSOLE.store(console as u8, Ordering::Relaxed);
+            info!("platform: console through {:?}", console);
+        }
+        Mode::Machine => {
+            info!("platform: M-mode, no firmware");
+            if !clint::init(fdt) {
+                warn!("platform: no CLINT, IPIs and the timer are unavailable");
+            }
+        }
+    }
+    // M-mode has nothing else to power off with, in S-mode it's the fallback for firmware without SRST
+    let find = |compatible| {
+        fdt.find_compatible(&[compatible])
+            .and_then(|node| SysconWrite::from_node(fdt, node))
+    };
+    let syscon = SYSCON.call_once(|| Syscon {
+        poweroff: find("syscon-poweroff"),
+        reboot: find("syscon-reboot"),
+    });
+    debug!("platform: {:?}", syscon);
+}
+
+/// raise a software interrupt on `hart`, false if there's no way to
+pub fn send_ipi(hart: usize) -> bool {
+    if has_sbi() {
+        HAS_IPI.load(Ordering::Relaxed) && sbi::send_ipi(1, hart).is_ok()
+    } else {
+        clint::set_msip(hart, true)
+    }
+}
+
+/// acknowledge a software interrupt on the calling hart
+pub fn clear_ipi(hart: usize) {
+    if has_sbi() {
+        csr_clear!("sip", SIP_SSIP);
+    } else {
+        clint::set_msip(hart, false);
+    }
+}
+
+/// start a stopped hart at `start_addr` with a0 = hartid and a1 = `opaque`.
+/// only possible through SBI HSM, without a firmware the harts are already running
+pub fn start_hart(hart: usize, start_addr: usize, opaque: usize) -> Result<(), isize> {
+    if !has_sbi() || !HAS_HSM.load(Ordering::Relaxed) {
+        return Err(sbi::ERR_NOT_SUPPORTED);
+    }
+    let ret = sbi::hart_start(hart, start_addr, opaque);
+    if ret.is_ok() {
+        Ok(())
+    } else {
+        Err(ret.error)
+    }
+}
+
+fn halt() -> ! {
+    println!("unable to power off or reset, halting");
+    csr::disable_interrupts();
+    loop {
+        csr::wfi();
+    }
+}
+
+/// power the machine off
+pub fn shutdown() -> ! {
+    if has_sbi() && HAS_SRST.load(Ordering::Relaxed) {
+        sbi::system_reset(sbi::ResetType::Shutdown, sbi::ResetReason::None);
+    }
+    if let Some(poweroff) = SYSCON.get().and_then(|syscon| syscon.poweroff) {
+        poweroff.write();
+    }
+    if has_sbi() {
+        sbi::legacy_shutdown();
+    }
+    halt()
+}
+
+/// reset the machine
+pub fn reboot() -> ! {
+    if has_sbi() && HAS_SRST.load(Ordering::Relaxed) {
+        sbi::system_reset(sbi::ResetType::ColdReboot, sbi::ResetReason::None);
+    }
+    if let Some(reboot) = SYSCON.get().and_then(|syscon| syscon.reboot) {
+        reboot.write();
+    }
+    halt()
+}
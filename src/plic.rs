@@ -3,13 +3,35 @@
 // Stephen Marz
 // 1 Nov 2019
 #![allow(dead_code)]
-use crate::println;
+use crate::{
+    csr::{self, Mode},
+    executor, println, smp,
+    sync::IrqSafeSpinlock,
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::sync::atomic::Ordering;
+use fdt::Fdt;
+use log::*;
+use spin::Once;
 
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const PLIC_PENDING: usize = 0x0c00_1000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+// Register offsets from the PLIC base address. The base itself comes
+// from the device tree, see `Plic::from_fdt`.
+const PLIC_PRIORITY: usize = 0x0000;
+const PLIC_PENDING: usize = 0x1000;
+const PLIC_INT_ENABLE: usize = 0x2000;
+// Each context gets its own 0x80 bytes of enable bits...
+const PLIC_ENABLE_STRIDE: usize = 0x80;
+const PLIC_THRESHOLD: usize = 0x20_0000;
+const PLIC_CLAIM: usize = 0x20_0004;
+// ...and its own 0x1000 bytes of threshold/claim registers.
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+// The spec allows ids 1..=1023, 0 is reserved.
+const PLIC_MAX_ID: u32 = 1023;
+
+// Interrupt numbers a hart's local interrupt controller uses in
+// `interrupts-extended` for external interrupts to each mode.
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_EXT: u32 = 11;
 
 // Each register is 4-bytes (u32)
 // The PLIC is an external interrupt controller. The one
@@ -24,100 +46,340 @@ const PLIC_CLAIM: usize = 0x0c20_0004;
 // UART0 = 10
 // PCIE = [32..35]
 
-/// Get the next available interrupt. This is the "claim" process.
-/// The plic will automatically sort by priority and hand us the
-/// ID of the interrupt. For example, if the UART is interrupting
-/// and it's next, we will get the value 10.
-pub fn next() -> Option<u32> {
-    let claim_reg = PLIC_CLAIM as *const u32;
-    let claim_no;
-    // The claim register is filled with the highest-priority, enabled interrupt.
-    unsafe {
-        claim_no = claim_reg.read_volatile();
+/// A context is one hart at one privilege level. Every context has
+/// its own enable bits, threshold and claim register.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Context {
+    pub hart: usize,
+    pub mode: Mode,
+    pub index: usize,
+}
+
+/// A PLIC as described by a `riscv,plic0` or `sifive,plic-1.0.0` node.
+#[derive(Clone, Debug)]
+pub struct Plic {
+    base: usize,
+    /// number of interrupt sources, ids are 1..=ndev
+    ndev: u32,
+    contexts: Vec<Context>,
+}
+
+impl Plic {
+    /// Build the PLIC from the device tree. The contexts come from
+    /// `interrupts-extended`, which lists one (cpu interrupt controller,
+    /// interrupt number) pair per context, in context order.
+    pub fn from_fdt(fdt: &Fdt) -> Option<Self> {
+        let node = fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"])?;
+        let base = node.reg()?.next()?.starting_address as usize;
+        let ndev = node
+            .property("riscv,ndev")
+            .and_then(|prop| prop.as_usize())
+            .map_or(PLIC_MAX_ID, |ndev| (ndev as u32).min(PLIC_MAX_ID));
+
+        // Map the phandle of every hart's interrupt controller to the hart id.
+        let mut harts = BTreeMap::new();
+        for cpu in fdt.find_node("/cpus")?.children() {
+            let Some(hart) = cpu.property("reg").and_then(|reg| reg.as_usize()) else {
+                continue;
+            };
+            for intc in cpu.children() {
+                if let Some(phandle) = intc.property("phandle").and_then(|p| p.as_usize()) {
+                    harts.insert(phandle, hart);
+                }
+            }
+        }
+
+        let mut contexts = Vec::new();
+        let cells = node.property("interrupts-extended")?.value;
+        for (index, pair) in cells.chunks_exact(8).enumerate() {
+            let phandle = u32::from_be_bytes(pair[0..4].try_into().unwrap()) as usize;
+            let irq = u32::from_be_bytes(pair[4..8].try_into().unwrap());
+            let mode = match irq {
+                IRQ_M_EXT => Mode::Machine,
+                IRQ_S_EXT => Mode::Supervisor,
+                // 0xffffffff marks a context that isn't wired up.
+                _ => continue,
+            };
+            if let Some(&hart) = harts.get(&phandle) {
+                contexts.push(Context { hart, mode, index });
+            }
+        }
+        Some(Self {
+            base,
+            ndev,
+            contexts,
+        })
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn ndev(&self) -> u32 {
+        self.ndev
+    }
+
+    pub fn contexts(&self) -> &[Context] {
+        &self.contexts
+    }
+
+    /// Find the context index for a hart at a privilege level.
+    pub fn context(&self, hart: usize, mode: Mode) -> Option<usize> {
+        self.contexts
+            .iter()
+            .find(|ctx| ctx.hart == hart && ctx.mode == mode)
+            .map(|ctx| ctx.index)
+    }
+
+    fn valid_id(&self, id: u32) -> bool {
+        id != 0 && id <= self.ndev
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    /// Get the next available interrupt for a context. This is the "claim" process.
+    /// The plic will automatically sort by priority and hand us the
+    /// ID of the interrupt. For example, if the UART is interrupting
+    /// and it's next, we will get the value 10.
+    pub fn claim(&self, context: usize) -> Option<u32> {
+        let claim_reg = self.reg(PLIC_CLAIM + PLIC_CONTEXT_STRIDE * context);
+        let claim_no;
+        // The claim register is filled with the highest-priority, enabled interrupt.
+        unsafe {
+            claim_no = claim_reg.read_volatile();
+        }
+        if claim_no == 0 {
+            // The interrupt 0 is hardwired to 0, which tells us that there is no
+            // interrupt to claim, hence we return None.
+            None
+        } else {
+            // If we get here, we've gotten a non-0 interrupt.
+            Some(claim_no)
+        }
+    }
+
+    /// Complete a pending interrupt by id. The id should come
+    /// from claim() on the same context.
+    pub fn complete(&self, context: usize, id: u32) {
+        let complete_reg = self.reg(PLIC_CLAIM + PLIC_CONTEXT_STRIDE * context);
+        unsafe {
+            // We actually write a u32 into the entire complete_register.
+            // This is the same register as the claim register, but it can
+            // differentiate based on whether we're reading or writing.
+            complete_reg.write_volatile(id);
+        }
+    }
+
+    /// Set a context's threshold. The threshold can be a value [0..7].
+    /// The PLIC will mask any interrupts at or below the given threshold.
+    /// This means that a threshold of 7 will mask ALL interrupts and
+    /// a threshold of 0 will allow ALL interrupts.
+    pub fn set_threshold(&self, context: usize, tsh: u8) {
+        // We do tsh because we're using a u8, but our maximum number
+        // is a 3-bit 0b111. So, we and with 7 (0b111) to just get the
+        // last three bits.
+        let actual_tsh = tsh & 7;
+        let tsh_reg = self.reg(PLIC_THRESHOLD + PLIC_CONTEXT_STRIDE * context);
+        unsafe {
+            tsh_reg.write_volatile(actual_tsh as u32);
+        }
+    }
+
+    /// See if a given interrupt id is pending.
+    pub fn is_pending(&self, id: u32) -> bool {
+        if !self.valid_id(id) {
+            return false;
+        }
+        // The pending registers are an array of 32-bit words, one bit per id.
+        let pend = self.reg(PLIC_PENDING + 4 * (id as usize / 32));
+        let actual_id = 1 << (id % 32);
+        let pend_ids;
+        unsafe {
+            pend_ids = pend.read_volatile();
+        }
+        actual_id & pend_ids != 0
+    }
+
+    fn enable_reg(&self, context: usize, id: u32) -> *mut u32 {
+        self.reg(PLIC_INT_ENABLE + PLIC_ENABLE_STRIDE * context + 4 * (id as usize / 32))
+    }
+
+    /// Enable a given interrupt id for a context
+    pub fn enable(&self, context: usize, id: u32) {
+        if !self.valid_id(id) {
+            warn!("plic: interrupt id {} out of range 1..={}", id, self.ndev);
+            return;
+        }
+        let enables = self.enable_reg(context, id);
+        let actual_id = 1 << (id % 32);
+        unsafe {
+            // Unlike the complete and claim registers, the plic_int_enable
+            // register is a bitset where the id is the bit index. Each word
+            // covers 32 ids, so ids past 31 (like the PCIe lines) live in the
+            // next word (0 is hardwired to 0).
+            enables.write_volatile(enables.read_volatile() | actual_id);
+        }
+    }
+
+    /// Disable a given interrupt id for a context
+    pub fn disable(&self, context: usize, id: u32) {
+        if !self.valid_id(id) {
+            return;
+        }
+        let enables = self.enable_reg(context, id);
+        let actual_id = 1 << (id % 32);
+        unsafe {
+            enables.write_volatile(enables.read_volatile() & !actual_id);
+        }
     }
-    if claim_no == 0 {
-        // The interrupt 0 is hardwired to 0, which tells us that there is no
-        // interrupt to claim, hence we return None.
-        None
-    } else {
-        // If we get here, we've gotten a non-0 interrupt.
-        Some(claim_no)
+
+    /// Set a given interrupt priority to the given priority.
+    /// The priority must be [0..7]
+    pub fn set_priority(&self, id: u32, prio: u8) {
+        if !self.valid_id(id) {
+            warn!("plic: interrupt id {} out of range 1..={}", id, self.ndev);
+            return;
+        }
+        let actual_prio = prio as u32 & 7;
+        let prio_reg = self.reg(PLIC_PRIORITY);
+        unsafe {
+            // The offset for the interrupt id is:
+            // PLIC_PRIORITY + 4 * id
+            // Since we're using pointer arithmetic on a u32 type,
+            // it will automatically multiply the id by 4.
+            prio_reg.add(id as usize).write_volatile(actual_prio);
+        }
     }
 }
 
-/// Complete a pending interrupt by id. The id should come
-/// from the next() function above.
+static PLIC: Once<Plic> = Once::new();
+
+/// Find the PLIC in the device tree and pick the context for the calling
+/// hart in the mode we are running in. Returns false if there is no usable PLIC.
+pub fn init(fdt: &Fdt) -> bool {
+    let Some(plic) = Plic::from_fdt(fdt) else {
+        warn!("plic: no riscv,plic0 node in the device tree");
+        return false;
+    };
+    info!("plic: base {:#x}, {} sources", plic.base(), plic.ndev());
+    PLIC.call_once(|| plic);
+    init_hart()
+}
+
+/// Pick the context for the calling hart, once `init` has found the PLIC.
+/// The threshold starts at 0 but nothing is enabled for the context yet.
+pub fn init_hart() -> bool {
+    let hart = smp::current_hart();
+    let Some(context) = plic().and_then(|plic| plic.context(hart.hart_id(), csr::mode())) else {
+        warn!(
+            "plic: no context for hart {} in {:?} mode",
+            hart.hart_id(),
+            csr::mode()
+        );
+        return false;
+    };
+    debug!("plic: hart {} uses context {}", hart.hart_id(), context);
+    hart.plic_context.store(context, Ordering::Relaxed);
+    set_threshold(0);
+    true
+}
+
+/// The PLIC found by `init`, if any.
+pub fn plic() -> Option<&'static Plic> {
+    PLIC.get()
+}
+
+fn local() -> Option<(&'static Plic, usize)> {
+    let context = smp::current_hart().plic_context.load(Ordering::Relaxed);
+    plic()
+        .filter(|_| context != usize::MAX)
+        .map(|plic| (plic, context))
+}
+
+/// Claim the next interrupt for this hart.
+pub fn next() -> Option<u32> {
+    local().and_then(|(plic, context)| plic.claim(context))
+}
+
+/// Complete an interrupt claimed with next().
 pub fn complete(id: u32) {
-    let complete_reg = PLIC_CLAIM as *mut u32;
-    unsafe {
-        // We actually write a u32 into the entire complete_register.
-        // This is the same register as the claim register, but it can
-        // differentiate based on whether we're reading or writing.
-        complete_reg.write_volatile(id);
+    if let Some((plic, context)) = local() {
+        plic.complete(context, id)
     }
 }
 
-/// Set the global threshold. The threshold can be a value [0..7].
-/// The PLIC will mask any interrupts at or below the given threshold.
-/// This means that a threshold of 7 will mask ALL interrupts and
-/// a threshold of 0 will allow ALL interrupts.
+/// Set the threshold for this hart.
 pub fn set_threshold(tsh: u8) {
-    // We do tsh because we're using a u8, but our maximum number
-    // is a 3-bit 0b111. So, we and with 7 (0b111) to just get the
-    // last three bits.
-    let actual_tsh = tsh & 7;
-    let tsh_reg = PLIC_THRESHOLD as *mut u32;
-    unsafe {
-        tsh_reg.write_volatile(actual_tsh as u32);
+    if let Some((plic, context)) = local() {
+        plic.set_threshold(context, tsh)
     }
 }
 
 /// See if a given interrupt id is pending.
 pub fn is_pending(id: u32) -> bool {
-    let pend = PLIC_PENDING as *const u32;
-    let actual_id = 1 << id;
-    let pend_ids;
-    unsafe {
-        pend_ids = pend.read_volatile();
-    }
-    actual_id & pend_ids != 0
+    plic().map_or(false, |plic| plic.is_pending(id))
 }
 
-/// Enable a given interrupt id
+/// Enable a given interrupt id for this hart.
 pub fn enable(id: u32) {
-    let enables = PLIC_INT_ENABLE as *mut u32;
-    let actual_id = 1 << id;
-    unsafe {
-        // Unlike the complete and claim registers, the plic_int_enable
-        // register is a bitset where the id is the bit index. The register
-        // is a 32-bit register, so that gives us enables for interrupts
-        // 31 through 1 (0 is hardwired to 0).
-        enables.write_volatile(enables.read_volatile() | actual_id);
+    if let Some((plic, context)) = local() {
+        plic.enable(context, id)
+    }
+}
+
+/// Disable a given interrupt id for this hart.
+pub fn disable(id: u32) {
+    if let Some((plic, context)) = local() {
+        plic.disable(context, id)
     }
 }
 
 /// Set a given interrupt priority to the given priority.
-/// The priority must be [0..7]
 pub fn set_priority(id: u32, prio: u8) {
-    let actual_prio = prio as u32 & 7;
-    let prio_reg = PLIC_PRIORITY as *mut u32;
-    unsafe {
-        // The offset for the interrupt id is:
-        // PLIC_PRIORITY + 4 * id
-        // Since we're using pointer arithmetic on a u32 type,
-        // it will automatically multiply the id by 4.
-        prio_reg.add(id as usize).write_volatile(actual_prio);
+    if let Some(plic) = plic() {
+        plic.set_priority(id, prio)
     }
 }
 
+/// Something that wants to be told when an interrupt id fires.
+/// Lines can be shared (PCI INTx), so `handle` returns whether
+/// the device behind this handler was the one interrupting.
+pub trait IrqHandler: Sync {
+    fn handle(&self, id: u32) -> bool;
+}
+
+/// The trap handler takes this too, the lock keeps interrupts off while it's held.
+static HANDLERS: IrqSafeSpinlock<BTreeMap<u32, Vec<&'static dyn IrqHandler>>> =
+    IrqSafeSpinlock::new("irq handlers", BTreeMap::new());
+
+/// Register a handler for the given interrupt id, then give the id a
+/// priority and enable it. Several handlers may share one id.
+pub fn register_handler(id: u32, prio: u8, handler: &'static dyn IrqHandler) {
+    let mut handlers = HANDLERS.lock();
+    handlers.entry(id).or_default().push(handler);
+    set_priority(id, prio);
+    enable(id);
+}
+
 pub fn handle_interrupt() {
     if let Some(interrupt) = next() {
         // If we get here, we've got an interrupt from the claim register. The PLIC will
         // automatically prioritize the next interrupt, so when we get it from claim, it
         // will be the next in priority order.
-        {
+        let handled = match HANDLERS.lock().get(&interrupt) {
+            // Every handler on a shared line gets a look, more than one device may be asserting it.
+            Some(handlers) => handlers.iter().fold(false, |handled, handler| {
+                handler.handle(interrupt) | handled
+            }),
+            None => false,
+        };
+        if !handled {
             println!("Unknown external interrupt: {}", interrupt);
         }
+        // Futures waiting on this id get polled again.
+        executor::wake_irq(interrupt);
         // We've claimed it, so now say that we've handled it. This resets the interrupt pending
         // and allows the UART to interrupt again. Otherwise, the UART will get "stuck".
         complete(interrupt);
@@ -0,0 +1,236 @@
+// pmm.rs
+// physical memory manager, a buddy allocator over every usable page of RAM the device tree reports
+#![allow(dead_code)]
+use crate::{
+    layout::{self, Region},
+    sync::IrqSafeSpinlock,
+};
+use alloc::vec::Vec;
+use core::fmt;
+use fdt::Fdt;
+use log::*;
+
+pub const PAGE_SIZE: usize = 0x1000;
+/// the largest block is 2^MAX_ORDER pages (4MiB)
+pub const MAX_ORDER: usize = 10;
+
+/// free blocks of each order, linked through their first word
+struct BuddyAllocator {
+    free_lists: [usize; MAX_ORDER + 1],
+    /// pages handed to the allocator
+    total: usize,
+    /// pages currently free
+    free: usize,
+}
+
+impl BuddyAllocator {
+    const fn new() -> Self {
+        Self {
+            free_lists: [0; MAX_ORDER + 1],
+            total: 0,
+            free: 0,
+        }
+    }
+
+    const fn block_size(order: usize) -> usize {
+        PAGE_SIZE << order
+    }
+
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        (addr as *mut usize).write(self.free_lists[order]);
+        self.free_lists[order] = addr;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> Option<usize> {
+        let head = self.free_lists[order];
+        if head == 0 {
+            return None;
+        }
+        self.free_lists[order] = (head as *const usize).read();
+        Some(head)
+    }
+
+    /// unlink `addr` from the free list of `order`, returns false if it wasn't free
+    unsafe fn remove(&mut self, addr: usize, order: usize) -> bool {
+        let mut link = &mut self.free_lists[order] as *mut usize;
+        while *link != 0 {
+            if *link == addr {
+                *link = (addr as *const usize).read();
+                return true;
+            }
+            link = *link as *mut usize;
+        }
+        false
+    }
+
+    /// hand a range of RAM to the allocator, split into the largest aligned blocks that fit
+    unsafe fn add_range(&mut self, start: usize, end: usize) {
+        let mut start = align_up(start, PAGE_SIZE);
+        let end = end & !(PAGE_SIZE - 1);
+        while start < end {
+            let mut order = MAX_ORDER;
+            while start % Self::block_size(order) != 0 || start + Self::block_size(order) > end {
+                order -= 1;
+            }
+            self.push(start, order);
+            self.total += 1 << order;
+            self.free += 1 << order;
+            start += Self::block_size(order);
+        }
+    }
+
+    unsafe fn alloc(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != 0)?;
+        let block = self.pop(found)?;
+        // split the block, giving the upper halves back until it is the size we want
+        for split in (order..found).rev() {
+            self.push(block + Self::block_size(split), split);
+        }
+        self.free -= 1 << order;
+        Some(block)
+    }
+
+    unsafe fn free(&mut self, mut addr: usize, mut order: usize) {
+        self.free += 1 << order;
+        // merge with the buddy for as long as it is free too
+        while order < MAX_ORDER {
+            let buddy = addr ^ Self::block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+}
+
+// task stacks are freed from the scheduler with interrupts off, so this is taken with them off too
+static FRAMES: IrqSafeSpinlock<BuddyAllocator> =
+    IrqSafeSpinlock::new("frames", BuddyAllocator::new());
+
+/// page counts for reporting
+#[derive(Copy, Clone, Debug)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frames: {}/{} free ({} KiB free)",
+            self.free,
+            self.total,
+            self.free * PAGE_SIZE / 1024
+        )
+    }
+}
+
+const fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// the smallest order that holds `pages` pages
+pub fn order_for(pages: usize) -> usize {
+    pages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+/// allocate a physically contiguous, naturally aligned block of 2^order pages
+pub fn alloc(order: usize) -> Option<usize> {
+    if order > MAX_ORDER {
+        return None;
+    }
+    unsafe { FRAMES.lock().alloc(order) }
+}
+
+/// give back a block from `alloc`, `order` must match
+///
+/// # Safety
+/// the block must have come from `alloc` with the same order and must not be used afterwards
+pub unsafe fn free(addr: usize, order: usize) {
+    FRAMES.lock().free(addr, order);
+}
+
+/// uses `try_lock` so the panic handler can call it
+pub fn stats() -> Option<FrameStats> {
+    let frames = FRAMES.try_lock()?;
+    Some(FrameStats {
+        total: frames.total,
+        free: frames.free,
+    })
+}
+
+fn be_usize(bytes: &[u8]) -> Option<usize> {
+    match bytes.len() {
+        4 => Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize),
+        8 => Some(u64::from_be_bytes(bytes.try_into().ok()?) as usize),
+        _ => None,
+    }
+}
+
+/// everything inside RAM we must not hand out
+fn reserved_regions(fdt: &Fdt, fdt_region: Region) -> Vec<Region> {
+    let mut reserved = Vec::new();
+    // the image, stacks, static heap and the guard pages between them
+    reserved.push(layout::kernel_reserved());
+    reserved.push(fdt_region);
+    for reservation in fdt.memory_reservations() {
+        let start = reservation.address() as usize;
+        reserved.push(Region::new("memreserve", start, start + reservation.size()));
+    }
+    if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+        for child in reserved_memory.children() {
+            for reg in child.reg().into_iter().flatten() {
+                let start = reg.starting_address as usize;
+                reserved.push(Region::new(
+                    "reserved-memory",
+                    start,
+                    start + reg.size.unwrap_or(0),
+                ));
+            }
+        }
+    }
+    let chosen = fdt.find_node("/chosen");
+    let initrd_start = chosen
+        .and_then(|chosen| chosen.property("linux,initrd-start"))
+        .and_then(|prop| be_usize(prop.value));
+    let initrd_end = chosen
+        .and_then(|chosen| chosen.property("linux,initrd-end"))
+        .and_then(|prop| be_usize(prop.value));
+    if let (Some(start), Some(end)) = (initrd_start, initrd_end) {
+        reserved.push(Region::new("initrd", start, end));
+    }
+    reserved
+}
+
+/// give every page of RAM that isn't reserved to the frame allocator
+pub fn init(fdt: &Fdt, fdt_region: Region) {
+    let mut reserved = reserved_regions(fdt, fdt_region);
+    reserved.sort_by_key(|region| region.start);
+    for region in &reserved {
+        debug!("pmm: reserved {}", region);
+    }
+
+    let mut frames = FRAMES.lock();
+    for memory in fdt.memory().regions() {
+        let bank = memory.starting_address as usize;
+        let end = bank + memory.size.unwrap_or(0);
+        let mut start = bank;
+        // walk the sorted reservations, freeing the gaps between them
+        for region in reserved
+            .iter()
+            .filter(|region| region.end > bank && region.start < end)
+        {
+            if region.start > start {
+                unsafe { frames.add_range(start, region.start) };
+            }
+            start = start.max(region.end);
+        }
+        if start < end {
+            unsafe { frames.add_range(start, end) };
+        }
+    }
+    info!("pmm: {} KiB usable RAM", frames.total * PAGE_SIZE / 1024);
+}
@@ -0,0 +1,188 @@
+// sbi.rs
+// calls into the SBI firmware (OpenSBI) for things S-mode isn't allowed to touch itself
+#![allow(dead_code)]
+use core::arch::asm;
+
+/// the Timer extension, "TIME"
+pub const EXT_TIME: usize = 0x5449_4D45;
+
+/// what every SBI call hands back in a0/a1
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: isize,
+}
+
+impl SbiRet {
+    pub fn is_ok(&self) -> bool {
+        self.error == 0
+    }
+}
+
+/// make an SBI call with up to three arguments
+pub fn call(ext: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let error: isize;
+    let value: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") ext,
+        );
+    }
+    SbiRet { error, value }
+}
+
+/// program the next timer interrupt for this hart, this also clears a pending one
+pub fn set_timer(stime: u64) -> SbiRet {
+    call(EXT_TIME, 0, stime as usize, 0, 0)
+}
+
+/// the Base extension, always present
+pub const EXT_BASE: usize = 0x10;
+/// the legacy console putchar call, deprecated but still the only console some firmware has
+pub const EXT_LEGACY_PUTCHAR: usize = 0x01;
+/// the legacy console getchar call
+pub const EXT_LEGACY_GETCHAR: usize = 0x02;
+/// the legacy shutdown call
+pub const EXT_LEGACY_SHUTDOWN: usize = 0x08;
+/// the Debug Console extension, "DBCN"
+pub const EXT_DBCN: usize = 0x4442_434E;
+/// the IPI extension, "sPI"
+pub const EXT_IPI: usize = 0x0073_5049;
+/// the Hart State Management extension, "HSM"
+pub const EXT_HSM: usize = 0x0048_534D;
+/// the System Reset extension, "SRST"
+pub const EXT_SRST: usize = 0x5352_5354;
+
+/// SBI error codes
+pub const ERR_NOT_SUPPORTED: isize = -2;
+pub const ERR_INVALID_PARAM: isize = -3;
+pub const ERR_ALREADY_AVAILABLE: isize = -6;
+
+/// the version of the SBI spec the firmware implements, major in bits 24..31
+pub fn spec_version() -> (usize, usize) {
+    let version = call(EXT_BASE, 0, 0, 0, 0).value as usize;
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
+
+/// which firmware this is, 1 is OpenSBI
+pub fn impl_id() -> usize {
+    call(EXT_BASE, 1, 0, 0, 0).value as usize
+}
+
+pub fn impl_version() -> usize {
+    call(EXT_BASE, 2, 0, 0, 0).value as usize
+}
+
+/// does the firmware implement `ext`
+pub fn probe_extension(ext: usize) -> bool {
+    call(EXT_BASE, 3, ext, 0, 0).value != 0
+}
+
+/// print a byte through the legacy console
+pub fn legacy_putchar(byte: u8) {
+    call(EXT_LEGACY_PUTCHAR, 0, byte as usize, 0, 0);
+}
+
+/// read a byte from the legacy console, `None` if nothing is waiting
+pub fn legacy_getchar() -> Option<u8> {
+    // the legacy calls return the value in a0, where `call` puts the error
+    let ret = call(EXT_LEGACY_GETCHAR, 0, 0, 0, 0).error;
+    (ret >= 0).then_some(ret as u8)
+}
+
+/// power off through the legacy call, only returns if that isn't implemented either
+pub fn legacy_shutdown() {
+    call(EXT_LEGACY_SHUTDOWN, 0, 0, 0, 0);
+}
+
+/// print a byte through the debug console
+pub fn console_write_byte(byte: u8) -> SbiRet {
+    call(EXT_DBCN, 2, byte as usize, 0, 0)
+}
+
+/// read up to `buf.len()` bytes from the debug console, returns how many arrived
+pub fn console_read(buf: &mut [u8]) -> Result<usize, isize> {
+    let ret = call(EXT_DBCN, 1, buf.len(), buf.as_mut_ptr() as usize, 0);
+    if ret.is_ok() {
+        Ok(ret.value as usize)
+    } else {
+        Err(ret.error)
+    }
+}
+
+/// send a software interrupt to the harts in `hart_mask`, bit 0 being `hart_mask_base`
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    call(EXT_IPI, 0, hart_mask, hart_mask_base, 0)
+}
+
+/// what `hart_status` reports
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(isize),
+}
+
+impl From<isize> for HartState {
+    fn from(value: isize) -> Self {
+        match value {
+            0 => Self::Started,
+            1 => Self::Stopped,
+            2 => Self::StartPending,
+            3 => Self::StopPending,
+            4 => Self::Suspended,
+            5 => Self::SuspendPending,
+            6 => Self::ResumePending,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+/// start a stopped hart at `start_addr` in S-mode with translation off, a0 = hartid and a1 = `opaque`
+pub fn hart_start(hart: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    call(EXT_HSM, 0, hart, start_addr, opaque)
+}
+
+/// stop the calling hart, only returns on failure
+pub fn hart_stop() -> SbiRet {
+    call(EXT_HSM, 1, 0, 0, 0)
+}
+
+pub fn hart_status(hart: usize) -> Result<HartState, isize> {
+    let ret = call(EXT_HSM, 2, hart, 0, 0);
+    if ret.is_ok() {
+        Ok(HartState::from(ret.value))
+    } else {
+        Err(ret.error)
+    }
+}
+
+/// `system_reset` types
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+/// `system_reset` reasons
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/// reset or power off the system, only returns on failure
+pub fn system_reset(kind: ResetType, reason: ResetReason) -> SbiRet {
+    call(EXT_SRST, 0, kind as usize, reason as usize, 0)
+}
@@ -0,0 +1,533 @@
+// sched.rs
+// kernel threads: each task has its own stack and saved registers, and every hart runs its own queue.
+// tasks switch when they yield, sleep, join or exit, and the timer preempts them once their slice is up
+#![allow(dead_code)]
+use crate::{
+    csr,
+    layout::{self, Region, MAX_HARTS},
+    paging,
+    pmm::{self, PAGE_SIZE},
+    smp::{self, IpiMessage},
+    sync::IrqSafeSpinlock,
+    time::{self, Instant},
+};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    arch::global_asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use spin::Mutex;
+
+/// task stacks are 2^STACK_ORDER pages (64KiB), the same as the hart stacks
+const STACK_ORDER: usize = 4;
+pub const STACK_SIZE: usize = PAGE_SIZE << STACK_ORDER;
+/// each stack is the upper half of a block of twice its size from pmm, with its guard page right
+/// below it and the rest of the lower half handed straight back. that keeps the stacks aligned to
+/// their size and their guards at a fixed offset, which is all the trap vector has to go on
+pub const STACK_SLOT_SIZE: usize = PAGE_SIZE << (STACK_ORDER + 1);
+/// how long a task runs before the timer hands the hart to the next one
+pub const TIMESLICE: Duration = Duration::from_millis(10);
+
+/// the callee saved registers, everything else is saved by whoever called `switch_context`
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+global_asm!(
+    ".section .text",
+    ".global switch_context",
+    // a0 = where to save the current registers, a1 = the registers to load
+    "switch_context:",
+    "sd ra, 0*8(a0)",
+    "sd sp, 1*8(a0)",
+    "sd s0, 2*8(a0)",
+    "sd s1, 3*8(a0)",
+    "sd s2, 4*8(a0)",
+    "sd s3, 5*8(a0)",
+    "sd s4, 6*8(a0)",
+    "sd s5, 7*8(a0)",
+    "sd s6, 8*8(a0)",
+    "sd s7, 9*8(a0)",
+    "sd s8, 10*8(a0)",
+    "sd s9, 11*8(a0)",
+    "sd s10, 12*8(a0)",
+    "sd s11, 13*8(a0)",
+    "ld ra, 0*8(a1)",
+    "ld sp, 1*8(a1)",
+    "ld s0, 2*8(a1)",
+    "ld s1, 3*8(a1)",
+    "ld s2, 4*8(a1)",
+    "ld s3, 5*8(a1)",
+    "ld s4, 6*8(a1)",
+    "ld s5, 7*8(a1)",
+    "ld s6, 8*8(a1)",
+    "ld s7, 9*8(a1)",
+    "ld s8, 10*8(a1)",
+    "ld s9, 11*8(a1)",
+    "ld s10, 12*8(a1)",
+    "ld s11, 13*8(a1)",
+    "ret",
+);
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context);
+}
+
+pub struct Task {
+    id: usize,
+    name: &'static str,
+    /// the hart whose queue this task lives on, tasks never migrate
+    hart: usize,
+    context: UnsafeCell<Context>,
+    /// bottom of the stack, `None` for a hart's original flow which keeps its boot stack
+    stack: Option<usize>,
+    entry: UnsafeCell<Option<Box<dyn FnOnce()>>>,
+    finished: AtomicBool,
+    /// tasks blocked in `join` on this one
+    joiners: Mutex<Vec<Arc<Task>>>,
+    /// tick count to wake up at while sleeping
+    wake_at: AtomicU64,
+}
+
+// a task is only ever run (and its context and entry only touched) on its home hart, with
+// interrupts off while the scheduler has its hands on it
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+impl Task {
+    fn new(name: &'static str, hart: usize, stack: Option<usize>) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            hart,
+            context: UnsafeCell::new(Context::default()),
+            stack,
+            entry: UnsafeCell::new(None),
+            finished: AtomicBool::new(false),
+            joiners: Mutex::new(Vec::new()),
+            wake_at: AtomicU64::new(0),
+        }
+    }
+
+    /// a task with its own stack that starts in `task_entry`
+    fn with_stack(name: &'static str, hart: usize, entry: Box<dyn FnOnce()>) -> Arc<Self> {
+        let slot = pmm::alloc(STACK_ORDER + 1).expect("out of memory for a task stack");
+        let stack = slot + STACK_SIZE;
+        let guard = stack - layout::GUARD_SIZE;
+        // all of the lower half but the guard goes back, biggest piece first
+        let mut spare = slot;
+        for order in (0..STACK_ORDER).rev() {
+            unsafe { pmm::free(spare, order) };
+            spare += PAGE_SIZE << order;
+        }
+        debug_assert_eq!(spare, guard);
+        paging::unmap_guard(guard);
+        let task = Self::new(name, hart, Some(stack));
+        unsafe {
+            let context = &mut *task.context.get();
+            context.ra = task_entry as usize;
+            context.sp = stack + STACK_SIZE;
+            *task.entry.get() = Some(entry);
+        }
+        Arc::new(task)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack {
+            // pmm keeps its free lists in the blocks themselves, so the guard has to be mapped again
+            let guard = stack - layout::GUARD_SIZE;
+            paging::remap_guard(guard);
+            unsafe {
+                pmm::free(guard, 0);
+                pmm::free(stack, STACK_ORDER);
+            }
+        }
+    }
+}
+
+/// the task stack `addr` is on, if it is on one, by where `Task::with_stack` puts them.
+/// like the other stacks, the top itself counts and the bottom doesn't
+pub fn task_stack_of(addr: usize) -> Option<Region> {
+    let below = addr.checked_sub(1)?;
+    if layout::kernel_reserved().contains(below) {
+        return None;
+    }
+    let stack = (below & !(STACK_SLOT_SIZE - 1)) + STACK_SIZE;
+    (below >= stack).then(|| Region::new("task stack", stack, stack + STACK_SIZE))
+}
+
+/// the task stack whose guard page `addr` is in. all the other RAM outside the kernel image is
+/// mapped, so an unmapped page at a guard's offset is one
+pub fn task_stack_guarded_by(addr: usize) -> Option<Region> {
+    if layout::kernel_reserved().contains(addr) {
+        return None;
+    }
+    let stack = (addr & !(STACK_SLOT_SIZE - 1)) + STACK_SIZE;
+    let in_guard = (stack - layout::GUARD_SIZE..stack).contains(&addr)
+        && paging::enabled()
+        && paging::virt_to_phys(addr).is_none();
+    in_guard.then(|| Region::new("task stack", stack, stack + STACK_SIZE))
+}
+
+/// what happens to the task we switched away from, done by whoever runs next
+/// so the task is never visible to anyone else before its registers are saved
+enum After {
+    /// back on the run queue
+    Requeue,
+    /// onto the sleep list until its `wake_at`
+    Sleep,
+    /// waiting for another task to finish
+    Join(Arc<Task>),
+    /// gone, wake whoever is joining it
+    Exit,
+    /// it was the idle task, which is never queued
+    Idle,
+}
+
+struct HartSched {
+    run_queue: VecDeque<Arc<Task>>,
+    sleepers: Vec<Arc<Task>>,
+    current: Option<Arc<Task>>,
+    idle: Option<Arc<Task>>,
+    after: Option<(Arc<Task>, After)>,
+    /// tick count at which the current task's slice is up
+    slice_end: u64,
+}
+
+impl HartSched {
+    const fn new() -> Self {
+        Self {
+            run_queue: VecDeque::new(),
+            sleepers: Vec::new(),
+            current: None,
+            idle: None,
+            after: None,
+            slice_end: u64::MAX,
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        let mut i = 0;
+        while i < self.sleepers.len() {
+            if self.sleepers[i].wake_at.load(Ordering::Relaxed) <= now {
+                let task = self.sleepers.swap_remove(i);
+                self.run_queue.push_back(task);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// ask for a timer interrupt at the next wake up or the end of the slice, whichever is first
+    fn arm_timer(&self) {
+        let sleeper = self
+            .sleepers
+            .iter()
+            .map(|task| task.wake_at.load(Ordering::Relaxed))
+            .min();
+        let slice = (!self.run_queue.is_empty()).then_some(self.slice_end);
+        if let Some(deadline) = sleeper.into_iter().chain(slice).min() {
+            time::arm(Instant::from_ticks(deadline));
+        }
+    }
+
+    fn is_idle(&self, task: &Arc<Task>) -> bool {
+        self.idle
+            .as_ref()
+            .map_or(false, |idle| Arc::ptr_eq(idle, task))
+    }
+}
+
+// only used to fill the array below, each element is its own static
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SCHED: IrqSafeSpinlock<HartSched> = IrqSafeSpinlock::new("sched", HartSched::new());
+/// indexed by hart id, the timer interrupt takes it too
+static SCHED: [IrqSafeSpinlock<HartSched>; MAX_HARTS] = [NO_SCHED; MAX_HARTS];
+
+/// put `task` on its hart's run queue, kicking that hart out of `wfi` if it isn't us
+fn wake(task: Arc<Task>) {
+    let hart = task.hart;
+    let mut sched = SCHED[hart].lock();
+    sched.run_queue.push_back(task);
+    if hart == smp::hart_id() {
+        // make sure the slice timer is running now that there's someone to switch to
+        sched.arm_timer();
+    }
+    drop(sched);
+    if hart != smp::hart_id() {
+        // the other hart re-arms its own timer when it takes the IPI
+        smp::send_ipi(hart, IpiMessage::Wake);
+    }
+}
+
+/// switch to the next task on this hart. does nothing if `after` is `Requeue` and nothing else
+/// is ready, otherwise falls back to the idle task
+fn schedule(after: After) {
+    // the lock alone isn't enough here: its guard has to go before `switch_context`, since the
+    // next task may never come back to drop it, yet nothing may interrupt us until the switch is
+    // finished on the other side. so interrupts stay off by hand from here to the end
+    let was_enabled = csr::disable_interrupts();
+    let hart = smp::hart_id();
+    let mut sched = SCHED[hart].lock();
+    let Some(prev) = sched.current.clone() else {
+        // the scheduler isn't running on this hart yet
+        drop(sched);
+        csr::restore_interrupts(was_enabled);
+        return;
+    };
+    let now = time::ticks();
+    sched.wake_sleepers(now);
+    let next = match sched.run_queue.pop_front() {
+        Some(next) => next,
+        None if matches!(after, After::Requeue | After::Idle) => {
+            drop(sched);
+            csr::restore_interrupts(was_enabled);
+            return;
+        }
+        None => sched.idle.clone().expect("sched: no idle task"),
+    };
+    let after = if sched.is_idle(&prev) {
+        After::Idle
+    } else {
+        after
+    };
+    let prev_context = prev.context.get();
+    let next_context = next.context.get();
+    sched.current = Some(next);
+    sched.after = Some((prev, after));
+    sched.slice_end = now + time::duration_to_ticks(TIMESLICE);
+    sched.arm_timer();
+    drop(sched);
+    unsafe { switch_context(prev_context, next_context) };
+    // we're back, possibly much later, on this task's stack
+    finish_switch();
+    csr::restore_interrupts(was_enabled);
+}
+
+/// deal with the task we just switched away from, see `After`
+fn finish_switch() {
+    let hart = smp::hart_id();
+    let mut sched = SCHED[hart].lock();
+    let Some((prev, after)) = sched.after.take() else {
+        return;
+    };
+    match after {
+        After::Requeue => sched.run_queue.push_back(prev),
+        After::Sleep => {
+            sched.sleepers.push(prev);
+            sched.arm_timer();
+        }
+        After::Idle => {}
+        After::Join(target) => {
+            drop(sched);
+            let mut joiners = target.joiners.lock();
+            if target.is_finished() {
+                drop(joiners);
+                wake(prev);
+            } else {
+                joiners.push(prev);
+            }
+        }
+        After::Exit => {
+            drop(sched);
+            prev.finished.store(true, Ordering::Release);
+            let joiners = core::mem::take(&mut *prev.joiners.lock());
+            for joiner in joiners {
+                wake(joiner);
+            }
+            // the last reference frees the stack, we're no longer on it
+            drop(prev);
+        }
+    }
+}
+
+/// where every task with its own stack starts
+extern "C" fn task_entry() -> ! {
+    finish_switch();
+    let entry = {
+        let task = current().unwrap();
+        unsafe { (*task.entry.get()).take() }.unwrap()
+    };
+    csr::enable_interrupts();
+    entry();
+    exit()
+}
+
+fn idle_loop() -> ! {
+    let hart = smp::hart_id();
+    loop {
+        // the masked check then `wfi` is the same as `time::wait_until`, a wake up can't slip in between
+        let was_enabled = csr::disable_interrupts();
+        let ready = !SCHED[hart].lock().run_queue.is_empty();
+        if ready {
+            csr::restore_interrupts(was_enabled);
+            schedule(After::Idle);
+        } else {
+            csr::wfi();
+            csr::enable_interrupts();
+        }
+    }
+}
+
+/// the task running on this hart, `None` before `init_hart`
+pub fn current() -> Option<Arc<Task>> {
+    SCHED[smp::hart_id()].lock().current.clone()
+}
+
+fn adopt(sched: &mut HartSched, name: &'static str) -> Arc<Task> {
+    let task = Arc::new(Task::new(name, smp::hart_id(), None));
+    sched.current = Some(task.clone());
+    sched.slice_end = time::ticks() + time::duration_to_ticks(TIMESLICE);
+    task
+}
+
+/// turn the code calling this into a task called `name` and start scheduling on this hart
+pub fn init_hart(name: &'static str) {
+    let hart = smp::hart_id();
+    let idle = Task::with_stack("idle", hart, Box::new(|| idle_loop()));
+    let mut sched = SCHED[hart].lock();
+    adopt(&mut sched, name);
+    sched.idle = Some(idle);
+}
+
+/// turn the calling hart's boot flow into its idle task, for secondary harts with nothing else to do
+pub fn run_idle() -> ! {
+    let mut sched = SCHED[smp::hart_id()].lock();
+    let idle = adopt(&mut sched, "idle");
+    sched.idle = Some(idle);
+    drop(sched);
+    idle_loop()
+}
+
+/// called from the trap handler after the timer or a wake up IPI, switches task if the slice is up
+pub fn tick() {
+    let hart = smp::hart_id();
+    let mut sched = SCHED[hart].lock();
+    let Some(current) = sched.current.clone() else {
+        return;
+    };
+    let now = time::ticks();
+    sched.wake_sleepers(now);
+    let preempt =
+        !sched.run_queue.is_empty() && (now >= sched.slice_end || sched.is_idle(&current));
+    sched.arm_timer();
+    drop(sched);
+    if preempt {
+        schedule(After::Requeue);
+    }
+}
+
+/// something for a task to wait on with `join`
+pub struct JoinHandle<T> {
+    task: Arc<Task>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn task(&self) -> &Arc<Task> {
+        &self.task
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// block until the task is done and take what it returned
+    pub fn join(self) -> T {
+        while !self.task.is_finished() {
+            if current().is_some() {
+                schedule(After::Join(self.task.clone()));
+            } else {
+                csr::wfi();
+            }
+        }
+        let result = self.result.lock().take();
+        result.expect("sched: joined task left no result")
+    }
+}
+
+fn spawn_inner<F, T>(name: &'static str, hart: usize, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let task = Task::with_stack(
+        name,
+        hart,
+        Box::new(move || {
+            *slot.lock() = Some(f());
+        }),
+    );
+    wake(task.clone());
+    JoinHandle { task, result }
+}
+
+/// start a task on the calling hart
+pub fn spawn<F, T>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    spawn_inner(name, smp::hart_id(), f)
+}
+
+/// start a task on another hart
+pub fn spawn_on<F, T>(hart: usize, name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    assert!(hart < MAX_HARTS, "sched: no hart {}", hart);
+    spawn_inner(name, hart, f)
+}
+
+/// let the next ready task run, if there is one
+pub fn yield_now() {
+    schedule(After::Requeue);
+}
+
+/// block this task for at least `duration`, other tasks run in the meantime
+pub fn sleep(duration: Duration) {
+    let Some(task) = current() else {
+        return time::sleep(duration);
+    };
+    let deadline = time::Timeout::after(duration).deadline().ticks();
+    task.wake_at.store(deadline, Ordering::Relaxed);
+    drop(task);
+    while time::ticks() < deadline {
+        schedule(After::Sleep);
+    }
+}
+
+/// end the calling task
+pub fn exit() -> ! {
+    schedule(After::Exit);
+    unreachable!("sched: exited task was scheduled again")
+}
@@ -0,0 +1,149 @@
+// selftest.rs
+// quick checks of the core kernel services, run at boot when the command line has `selftest`
+#![allow(dead_code)]
+use crate::{
+    cmdline, executor,
+    line_edit::{self, Editor, Key},
+    pmm, println, sched,
+    sync::IrqSafeSpinlock,
+    time,
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::time::Duration;
+use log::*;
+
+type Test = fn() -> Result<(), &'static str>;
+
+const TESTS: &[(&str, Test)] = &[
+    ("heap", heap),
+    ("frames", frames),
+    ("locks", locks),
+    ("tasks", tasks),
+    ("executor timers", timers),
+    ("line history", line_history),
+    ("cmdline", cmdline),
+];
+
+fn check(ok: bool, what: &'static str) -> Result<(), &'static str> {
+    ok.then_some(()).ok_or(what)
+}
+
+fn heap() -> Result<(), &'static str> {
+    let boxed = Box::new(0x5a5a_u64);
+    check(*boxed == 0x5a5a, "box lost its value")?;
+    let big: Vec<u32> = (0..4096).collect();
+    check(
+        big.iter().enumerate().all(|(i, &v)| v as usize == i),
+        "vec contents changed",
+    )
+}
+
+fn frames() -> Result<(), &'static str> {
+    let order = 2;
+    let block = pmm::alloc(order).ok_or("no free frames")?;
+    let second = pmm::alloc(order);
+    unsafe {
+        if let Some(second) = second {
+            pmm::free(second, order);
+        }
+        pmm::free(block, order);
+    }
+    check(
+        block % (pmm::PAGE_SIZE << order) == 0,
+        "block isn't naturally aligned",
+    )?;
+    check(second != Some(block), "same block handed out twice")
+}
+
+fn locks() -> Result<(), &'static str> {
+    let lock = IrqSafeSpinlock::new("selftest", 0);
+    let guard = lock.lock();
+    check(lock.try_lock().is_none(), "try_lock got a held lock")?;
+    drop(guard);
+    *lock.try_lock().ok_or("try_lock failed on a free lock")? += 1;
+    check(lock.into_inner() == 1, "write through the guard lost")
+}
+
+fn tasks() -> Result<(), &'static str> {
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            sched::spawn("selftest", move || {
+                sched::yield_now();
+                i * 2
+            })
+        })
+        .collect();
+    let sum: usize = handles.into_iter().map(|handle| handle.join()).sum();
+    check(sum == 12, "wrong results from joined tasks")
+}
+
+fn timers() -> Result<(), &'static str> {
+    let wait = Duration::from_millis(20);
+    let start = time::uptime();
+    executor::block_on(executor::sleep(wait));
+    check(time::uptime() - start >= wait, "sleep woke up early")
+}
+
+/// type `draft`, press `keys`, then enter. the output is never flushed, so none of it shows
+fn edit_draft(keys: &[Key]) -> Option<String> {
+    let mut editor = Editor::new();
+    "draft"
+        .chars()
+        .map(Key::Char)
+        .chain(keys.iter().copied())
+        .chain([Key::Enter])
+        .find_map(|key| editor.key(key))
+}
+
+fn line_history() -> Result<(), &'static str> {
+    line_edit::with_history(&["first", "second"], || {
+        check(
+            edit_draft(&[Key::Up, Key::Down, Key::Up]).as_deref() == Some("second"),
+            "up after down didn't recall the newest line",
+        )?;
+        check(
+            edit_draft(&[Key::Up, Key::Down, Key::Up, Key::Down]).as_deref() == Some("draft"),
+            "the typed line wasn't kept through up, down, up",
+        )?;
+        check(
+            edit_draft(&[Key::Up, Key::Up, Key::Up]).as_deref() == Some("first"),
+            "up went past the oldest line",
+        )
+    })
+}
+
+fn cmdline() -> Result<(), &'static str> {
+    let args = cmdline::parse(r#"a="x y" b c=1 c=2"#);
+    let keys: Vec<&str> = args.iter().map(|arg| arg.key.as_str()).collect();
+    check(keys == ["a", "b", "c", "c"], "wrong keys")?;
+    check(
+        cmdline::value_in(&args, "a") == Some("x y"),
+        "quotes didn't group",
+    )?;
+    check(
+        args[1].value.is_none() && cmdline::value_in(&args, "b").is_none(),
+        "a flag got a value",
+    )?;
+    check(
+        cmdline::value_in(&args, "c") == Some("2"),
+        "the last value didn't win",
+    )
+}
+
+/// run every test, returns whether they all passed
+pub fn run() -> bool {
+    let mut failed = 0;
+    for (name, test) in TESTS {
+        match test() {
+            Ok(()) => println!("selftest {}: ok", name),
+            Err(why) => {
+                println!("selftest {}: FAILED, {}", name, why);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        error!("selftest: {} of {} failed", failed, TESTS.len());
+    }
+    failed == 0
+}
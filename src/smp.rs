@@ -0,0 +1,291 @@
+// smp.rs
+// bringing up the other harts, per-hart data reached through `tp`, and inter-processor interrupts
+#![allow(dead_code)]
+use crate::{
+    csr::{self, Mode},
+    layout::{self, MAX_HARTS},
+    paging, platform, plic, println, sched, time, trap,
+};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+use fdt::Fdt;
+use log::*;
+
+/// how long to wait for a hart to say it's online
+const START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// everything that belongs to one hart. `tp` points at the calling hart's block
+#[repr(C)]
+pub struct HartData {
+    /// top of this hart's stack, read by `_secondary_start` so it must stay the first field
+    stack_top: AtomicUsize,
+    hart_id: AtomicUsize,
+    online: AtomicBool,
+    /// `IpiMessage` bits waiting to be handled
+    ipi_pending: AtomicUsize,
+    ipi_count: AtomicUsize,
+    /// the PLIC context for this hart in the mode we run in, `usize::MAX` if there is none
+    pub plic_context: AtomicUsize,
+    /// the earliest timer deadline programmed on this hart, `u64::MAX` when the timer is off
+    pub timer_deadline: AtomicU64,
+    /// the `TrapFrame` a panic on this hart was raised for, 0 if it wasn't raised by a trap
+    pub panic_frame: AtomicUsize,
+    /// top of this hart's trap stack, `trap::init_hart` puts it in `mscratch`/`sscratch`
+    trap_stack_top: AtomicUsize,
+}
+
+impl HartData {
+    const fn new() -> Self {
+        Self {
+            stack_top: AtomicUsize::new(0),
+            hart_id: AtomicUsize::new(usize::MAX),
+            online: AtomicBool::new(false),
+            ipi_pending: AtomicUsize::new(0),
+            ipi_count: AtomicUsize::new(0),
+            plic_context: AtomicUsize::new(usize::MAX),
+            timer_deadline: AtomicU64::new(u64::MAX),
+            panic_frame: AtomicUsize::new(0),
+            trap_stack_top: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn hart_id(&self) -> usize {
+        self.hart_id.load(Ordering::Relaxed)
+    }
+
+    pub fn trap_stack_top(&self) -> usize {
+        self.trap_stack_top.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// IPIs this hart has taken
+    pub fn ipi_count(&self) -> usize {
+        self.ipi_count.load(Ordering::Relaxed)
+    }
+}
+
+// only used to fill the arrays below, each element is its own static
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HART: HartData = HartData::new();
+/// indexed by hart id, so only harts below `MAX_HARTS` can be brought up
+static HARTS: [HartData; MAX_HARTS] = [NO_HART; MAX_HARTS];
+
+/// set by whichever hart gets into `_start` first, the rest park. in .data, not .bss,
+/// so it isn't wiped while the boot hart clears .bss
+#[link_section = ".data.smp"]
+pub static BOOT_CLAIMED: AtomicU32 = AtomicU32::new(0);
+/// M-mode release mailbox, the boot hart stores a `HartData` pointer here and sends an IPI
+#[link_section = ".data.smp"]
+static MAILBOX: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// messages carried by an IPI, several can be pending at once
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(usize)]
+pub enum IpiMessage {
+    /// nothing to do, just get out of `wfi`
+    Wake = 1 << 0,
+    /// stop taking interrupts and idle for good
+    Halt = 1 << 1,
+    /// the kernel page tables changed, drop the cached translations
+    FlushTlb = 1 << 2,
+}
+
+/// the calling hart's data
+pub fn current_hart() -> &'static HartData {
+    let tp: usize;
+    unsafe { asm!("mv {0}, tp", out(reg) tp) };
+    assert_ne!(tp, 0, "smp: tp isn't set up on this hart");
+    unsafe { &*(tp as *const HartData) }
+}
+
+/// the calling hart's id
+pub fn hart_id() -> usize {
+    current_hart().hart_id()
+}
+
+/// the calling hart's id, `None` before `tp` is set up
+pub fn try_hart_id() -> Option<usize> {
+    let tp: usize;
+    unsafe { asm!("mv {0}, tp", out(reg) tp) };
+    (tp != 0).then(|| unsafe { &*(tp as *const HartData) }.hart_id())
+}
+
+/// the data block for `hart`, if it is one we can bring up
+pub fn hart(hart: usize) -> Option<&'static HartData> {
+    HARTS.get(hart)
+}
+
+/// the harts that are up and running
+pub fn online_harts() -> impl Iterator<Item = &'static HartData> {
+    HARTS.iter().filter(|hart| hart.is_online())
+}
+
+/// point `tp` at the boot hart's data, first thing in `entry`
+pub fn init_boot_hart(hart_id: usize) {
+    let data = HARTS
+        .get(hart_id)
+        .unwrap_or_else(|| panic!("smp: boot hart {} is past MAX_HARTS", hart_id));
+    data.hart_id.store(hart_id, Ordering::Relaxed);
+    data.stack_top
+        .store(layout::init_stack().end, Ordering::Relaxed);
+    set_trap_stack(data, hart_id);
+    unsafe { asm!("mv tp, {0}", in(reg) data) };
+    data.online.store(true, Ordering::Release);
+}
+
+/// give the hart in slot `index` its trap stack, before it installs the trap vector
+fn set_trap_stack(data: &HartData, index: usize) {
+    let trap_stack = layout::trap_stack(index).expect("smp: no trap stack past MAX_HARTS");
+    data.trap_stack_top.store(trap_stack.end, Ordering::Relaxed);
+}
+
+/// queue `message` for `hart` and interrupt it
+pub fn send_ipi(hart: usize, message: IpiMessage) -> bool {
+    let Some(data) = HARTS.get(hart).filter(|data| data.is_online()) else {
+        return false;
+    };
+    data.ipi_pending
+        .fetch_or(message as usize, Ordering::Release);
+    platform::send_ipi(hart)
+}
+
+/// send `message` to every other online hart
+pub fn broadcast_ipi(message: IpiMessage) {
+    let me = hart_id();
+    for data in online_harts().filter(|data| data.hart_id() != me) {
+        send_ipi(data.hart_id(), message);
+    }
+}
+
+/// called from the trap handler on a software interrupt
+pub fn handle_ipi() {
+    let data = current_hart();
+    platform::clear_ipi(data.hart_id());
+    data.ipi_count.fetch_add(1, Ordering::Relaxed);
+    let pending = data.ipi_pending.swap(0, Ordering::Acquire);
+    if pending & IpiMessage::FlushTlb as usize != 0 {
+        unsafe { asm!("sfence.vma") };
+    }
+    if pending & IpiMessage::Halt as usize != 0 {
+        data.online.store(false, Ordering::Release);
+        csr::disable_interrupts();
+        loop {
+            csr::wfi();
+        }
+    }
+}
+
+/// where the M-mode losers of the boot race wait for their mailbox, entered from `_start` with a0 = hartid
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _park() -> ! {
+    asm!(
+        // harts we have no stack for stay here for good
+        "li t0, {max_harts}",
+        "bgeu a0, t0, 2f",
+        // wfi wakes on a pending software interrupt even with interrupts globally off
+        "li t0, 8",
+        "csrs mie, t0",
+        "1: wfi",
+        "la t0, {mailbox}",
+        "slli t1, a0, 3",
+        "add t0, t0, t1",
+        "ld a1, (t0)",
+        "beqz a1, 1b",
+        "fence r, rw",
+        "tail {secondary_start}",
+        "2: wfi",
+        "j 2b",
+        max_harts = const MAX_HARTS,
+        mailbox = sym MAILBOX,
+        secondary_start = sym _secondary_start,
+        options(noreturn)
+    );
+}
+
+/// where secondary harts start, a0 = hartid and a1 = their `HartData`.
+/// SBI HSM starts harts here directly, in M-mode they get here from `_park`
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _secondary_start() -> ! {
+    asm!(
+        ".option push",
+        ".option norelax",
+        "la gp, _global_pointer",
+        ".option pop",
+        "mv tp, a1",
+        "ld sp, 0(a1)",
+        "tail {entry}",
+        entry = sym secondary_entry,
+        options(noreturn)
+    );
+}
+
+extern "C" fn secondary_entry(hart_id: usize, data: &'static HartData) -> ! {
+    paging::init_hart();
+    // the release IPI is still pending in M-mode, drop it before interrupts go on
+    platform::clear_ipi(hart_id);
+    trap::init_hart();
+    plic::init_hart();
+    time::init_hart();
+    data.online.store(true, Ordering::Release);
+    // nothing else to do here, run whatever gets spawned on this hart
+    sched::run_idle()
+}
+
+/// start every other hart listed (and not disabled) in the device tree, returns how many came up
+pub fn start_secondaries(fdt: &Fdt) -> usize {
+    let me = hart_id();
+    let mut started = 0;
+    for cpu in fdt.cpus() {
+        let id = cpu.ids().first();
+        let disabled = cpu
+            .property("status")
+            .and_then(|prop| prop.as_str())
+            .map_or(false, |status| status != "okay");
+        if id == me || disabled {
+            continue;
+        }
+        let (Some(data), Some(stack)) = (HARTS.get(id), layout::hart_stack(id)) else {
+            warn!("smp: hart {} is past MAX_HARTS, leaving it parked", id);
+            continue;
+        };
+        data.hart_id.store(id, Ordering::Relaxed);
+        data.stack_top.store(stack.end, Ordering::Relaxed);
+        set_trap_stack(data, id);
+        let data_addr = data as *const HartData as usize;
+        match csr::mode() {
+            Mode::Supervisor => {
+                if let Err(error) = platform::start_hart(id, _secondary_start as usize, data_addr) {
+                    warn!("smp: SBI refused to start hart {}: {}", id, error);
+                    continue;
+                }
+            }
+            Mode::Machine => {
+                MAILBOX[id].store(data_addr, Ordering::Release);
+                if !platform::send_ipi(id) {
+                    warn!("smp: no way to wake hart {}", id);
+                    continue;
+                }
+            }
+        }
+        let came_up = time::wait_until(Some(time::Timeout::after(START_TIMEOUT)), || {
+            data.is_online().then_some(())
+        });
+        if came_up.is_some() {
+            started += 1;
+        } else {
+            println!("smp: hart {} didn't come up", id);
+        }
+    }
+    info!("smp: {} harts online", started + 1);
+    started
+}
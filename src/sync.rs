@@ -0,0 +1,416 @@
+// sync.rs
+// locks for kernel globals. they keep interrupts off while held, so an interrupt handler never spins
+// on a lock its own hart holds. debug builds also check that locks are always taken in the same order
+#![allow(dead_code)]
+use crate::{csr, smp, uart};
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+pub use spin::Once;
+
+/// `owner` of a lock nobody holds
+const NO_OWNER: usize = usize::MAX;
+/// how long to spin before saying a hart looks stuck, debug builds only
+const SPINS_BEFORE_WARNING: usize = 1 << 26;
+
+/// set once a panic starts, locks the panicking hart already holds are handed back to it
+/// instead of deadlocking the panic message
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// called first thing in the panic handlers
+pub fn begin_panic() {
+    PANICKING.store(true, Ordering::Relaxed);
+}
+
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// the bare locking algorithm under a `Lock`
+pub trait RawLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self;
+
+    fn try_acquire(&self) -> bool;
+    /// take the lock, calling `wait` every time around the spin loop
+    fn acquire(&self, wait: impl FnMut());
+    fn release(&self);
+    fn is_locked(&self) -> bool;
+}
+
+/// test-and-set, cheap but a hart can lose out to the others forever
+pub struct Spin {
+    locked: AtomicBool,
+}
+
+impl RawLock for Spin {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+    };
+
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn acquire(&self, mut wait: impl FnMut()) {
+        while !self.try_acquire() {
+            // spin on a plain load so the cache line isn't bounced around between harts
+            while self.is_locked() {
+                wait();
+            }
+        }
+    }
+
+    fn release(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// harts get the lock in the order they asked for it
+pub struct Ticket {
+    next: AtomicUsize,
+    serving: AtomicUsize,
+}
+
+impl RawLock for Ticket {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        next: AtomicUsize::new(0),
+        serving: AtomicUsize::new(0),
+    };
+
+    fn try_acquire(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    fn acquire(&self, mut wait: impl FnMut()) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            wait();
+        }
+    }
+
+    fn release(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+}
+
+/// a lock that disables interrupts on the holding hart
+#[repr(C)]
+pub struct Lock<R: RawLock, T: ?Sized> {
+    /// first, so the order checker can get the name back from the lock's address
+    name: &'static str,
+    raw: R,
+    /// hart holding the lock, `NO_OWNER` if free
+    owner: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+/// spins until free, for locks that are rarely contended
+pub type IrqSafeSpinlock<T> = Lock<Spin, T>;
+/// first come first served, for locks every hart hammers on (the console)
+pub type TicketLock<T> = Lock<Ticket, T>;
+
+unsafe impl<R: RawLock + Sync, T: ?Sized + Send> Sync for Lock<R, T> {}
+unsafe impl<R: RawLock + Send, T: ?Sized + Send> Send for Lock<R, T> {}
+
+impl<R: RawLock, T> Lock<R, T> {
+    /// `name` shows up in the deadlock and lock order reports
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Self {
+            name,
+            raw: R::INIT,
+            owner: AtomicUsize::new(NO_OWNER),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<R: RawLock, T: ?Sized> Lock<R, T> {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    /// `Some` if this hart already holds the lock: taking it again can only deadlock, so that's a
+    /// bug, unless we're panicking, then the panic gets to use the lock after all
+    fn held_here(&self, hart: Option<usize>, was_enabled: bool) -> Option<LockGuard<'_, R, T>> {
+        let hart = hart?;
+        if self.owner.load(Ordering::Relaxed) != hart {
+            return None;
+        }
+        if !panicking() {
+            panic!("sync: {} locked twice on hart {}", self.name, hart);
+        }
+        Some(LockGuard {
+            lock: self,
+            was_enabled,
+            stolen: true,
+        })
+    }
+
+    fn acquired(&self, hart: Option<usize>, was_enabled: bool) -> LockGuard<'_, R, T> {
+        self.owner
+            .store(hart.unwrap_or(NO_OWNER), Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        if let Some(hart) = hart {
+            order::acquired(hart, self.id());
+        }
+        LockGuard {
+            lock: self,
+            was_enabled,
+            stolen: false,
+        }
+    }
+
+    pub fn lock(&self) -> LockGuard<'_, R, T> {
+        let was_enabled = csr::disable_interrupts();
+        let hart = smp::try_hart_id();
+        if let Some(guard) = self.held_here(hart, was_enabled) {
+            return guard;
+        }
+        #[cfg(debug_assertions)]
+        if let Some(hart) = hart {
+            order::check(hart, self.id(), self.name);
+        }
+        let mut spins = 0usize;
+        self.raw.acquire(|| {
+            spins += 1;
+            if cfg!(debug_assertions) && spins == SPINS_BEFORE_WARNING {
+                let owner = self.owner.load(Ordering::Relaxed);
+                uart::print_unlocked(format_args!(
+                    "sync: hart {:?} looks stuck waiting for {} held by hart {}\r\n",
+                    hart, self.name, owner as isize
+                ));
+            }
+            spin_loop();
+        });
+        self.acquired(hart, was_enabled)
+    }
+
+    pub fn try_lock(&self) -> Option<LockGuard<'_, R, T>> {
+        let was_enabled = csr::disable_interrupts();
+        let hart = smp::try_hart_id();
+        if hart.is_some_and(|hart| self.owner.load(Ordering::Relaxed) == hart) && !panicking() {
+            // ours already, but trying is allowed to fail
+            csr::restore_interrupts(was_enabled);
+            return None;
+        }
+        if let Some(guard) = self.held_here(hart, was_enabled) {
+            return Some(guard);
+        }
+        if self.raw.try_acquire() {
+            // a lock that was free can't be part of a deadlock, so no order check
+            Some(self.acquired(hart, was_enabled))
+        } else {
+            csr::restore_interrupts(was_enabled);
+            None
+        }
+    }
+
+    /// the protected value without locking, it's up to the caller not to race the holder
+    pub fn data_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    /// access without locking, fine when nothing else can be running (early boot)
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<R: RawLock, T: ?Sized + fmt::Debug> fmt::Debug for Lock<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "Lock({}, {:?})", self.name, &*guard),
+            None => write!(f, "Lock({}, <locked>)", self.name),
+        }
+    }
+}
+
+pub struct LockGuard<'a, R: RawLock, T: ?Sized> {
+    lock: &'a Lock<R, T>,
+    was_enabled: bool,
+    /// handed out to a panic on a hart that already held it, the original guard still owns it
+    stolen: bool,
+}
+
+impl<R: RawLock, T: ?Sized> Deref for LockGuard<'_, R, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<R: RawLock, T: ?Sized> DerefMut for LockGuard<'_, R, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<R: RawLock, T: ?Sized> Drop for LockGuard<'_, R, T> {
+    fn drop(&mut self) {
+        if !self.stolen {
+            #[cfg(debug_assertions)]
+            if let Some(hart) = smp::try_hart_id() {
+                order::released(hart, self.lock.id());
+            }
+            self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+            self.lock.raw.release();
+        }
+        csr::restore_interrupts(self.was_enabled);
+    }
+}
+
+/// lock order checking: every time a lock is taken while others are held, remember that they came
+/// first. taking them the other way around later means two harts could each hold one and wait on
+/// the other, so it's reported even if it didn't deadlock this time
+#[cfg(debug_assertions)]
+mod order {
+    use super::panicking;
+    use crate::{layout::MAX_HARTS, uart};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// deepest nesting of locks we keep track of per hart
+    const MAX_HELD: usize = 16;
+    /// distinct (held, taken) pairs we remember
+    const MAX_EDGES: usize = 512;
+
+    struct Held {
+        depth: AtomicUsize,
+        locks: [AtomicUsize; MAX_HELD],
+    }
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_HELD: Held = Held {
+        depth: ZERO,
+        locks: [ZERO; MAX_HELD],
+    };
+    /// indexed by hart, only touched by that hart with interrupts off
+    static HELD: [Held; MAX_HARTS] = [NO_HELD; MAX_HARTS];
+
+    /// (first, then) lock ids, filled in from the front
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_EDGE: [AtomicUsize; 2] = [ZERO; 2];
+    static EDGES: [[AtomicUsize; 2]; MAX_EDGES] = [NO_EDGE; MAX_EDGES];
+    static EDGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static REPORTED: AtomicUsize = AtomicUsize::new(0);
+
+    fn held(hart: usize) -> Option<&'static Held> {
+        HELD.get(hart)
+    }
+
+    fn has_edge(first: usize, then: usize) -> bool {
+        let count = EDGE_COUNT.load(Ordering::Acquire).min(MAX_EDGES);
+        EDGES[..count].iter().any(|edge| {
+            edge[0].load(Ordering::Relaxed) == first && edge[1].load(Ordering::Relaxed) == then
+        })
+    }
+
+    fn add_edge(first: usize, then: usize) {
+        if has_edge(first, then) {
+            return;
+        }
+        let index = EDGE_COUNT.fetch_add(1, Ordering::AcqRel);
+        if let Some(edge) = EDGES.get(index) {
+            edge[1].store(then, Ordering::Relaxed);
+            edge[0].store(first, Ordering::Relaxed);
+        }
+    }
+
+    /// before spinning on `lock`, complain if something we hold has been taken after it before
+    pub fn check(hart: usize, lock: usize, name: &'static str) {
+        let Some(held) = held(hart) else {
+            return;
+        };
+        if panicking() {
+            return;
+        }
+        let depth = held.depth.load(Ordering::Relaxed).min(MAX_HELD);
+        for i in 0..depth {
+            let other = held.locks[i].load(Ordering::Relaxed);
+            if has_edge(lock, other) && REPORTED.fetch_add(1, Ordering::Relaxed) < 8 {
+                let other_name = name_of(other);
+                uart::print_unlocked(format_args!(
+                    "sync: lock order inversion on hart {}: taking {} while holding {}, \
+                     but {} has been held while taking {} before\r\n",
+                    hart, name, other_name, name, other_name
+                ));
+            }
+            add_edge(other, lock);
+        }
+    }
+
+    pub fn acquired(hart: usize, lock: usize) {
+        let Some(held) = held(hart) else {
+            return;
+        };
+        let depth = held.depth.load(Ordering::Relaxed);
+        if let Some(slot) = held.locks.get(depth) {
+            slot.store(lock, Ordering::Relaxed);
+        }
+        held.depth.store(depth + 1, Ordering::Relaxed);
+    }
+
+    pub fn released(hart: usize, lock: usize) {
+        let Some(held) = held(hart) else {
+            return;
+        };
+        let depth = held.depth.load(Ordering::Relaxed);
+        let tracked = depth.min(MAX_HELD);
+        // usually the last one taken, but guards can be dropped in any order
+        if let Some(i) = (0..tracked)
+            .rev()
+            .find(|&i| held.locks[i].load(Ordering::Relaxed) == lock)
+        {
+            for j in i..tracked - 1 {
+                let next = held.locks[j + 1].load(Ordering::Relaxed);
+                held.locks[j].store(next, Ordering::Relaxed);
+            }
+        }
+        held.depth.store(depth.saturating_sub(1), Ordering::Relaxed);
+    }
+
+    /// a lock id is the address of a `Lock`, which starts with its name. only used on locks
+    /// this hart holds, so the lock is still there
+    fn name_of(lock: usize) -> &'static str {
+        unsafe { *(lock as *const &'static str) }
+    }
+}
@@ -0,0 +1,504 @@
+// term.rs
+// a model of the terminal on the other end of an output: where the cursor is (skipping over escape
+// sequences, UTF-8 and tabs), how wide the screen is, whether to wrap, and styled text
+#![allow(dead_code)]
+use crate::time::{self, Timeout};
+use core::{fmt, str::FromStr, time::Duration};
+
+/// what a terminal writes its bytes to
+pub trait Sink {
+    fn write_bytes(&mut self, bytes: &[u8]);
+}
+
+/// when to break lines that run past the edge of the screen
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Wrap {
+    /// never, leave it to the terminal
+    Off,
+    /// after this many columns
+    At(u16),
+    /// at the terminal's width, 80 unless `query_size` found out otherwise
+    Width,
+}
+
+impl FromStr for Wrap {
+    type Err = ();
+
+    /// `off`, `width` or a column count
+    fn from_str(value: &str) -> Result<Self, ()> {
+        match value {
+            "off" | "no" => Ok(Self::Off),
+            "width" | "on" | "yes" => Ok(Self::Width),
+            columns => match columns.parse() {
+                Ok(0) | Err(_) => Err(()),
+                Ok(columns) => Ok(Self::At(columns)),
+            },
+        }
+    }
+}
+
+/// escape sequences longer than this are passed through without being understood
+const MAX_SEQUENCE: usize = 32;
+const TAB_WIDTH: u16 = 8;
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Ground,
+    /// after ESC
+    Escape,
+    /// in `ESC [ ... final`
+    Csi,
+    /// in `ESC ] ... BEL` (or `ESC \`)
+    Osc,
+    /// ESC inside an OSC, probably the start of its terminator
+    OscEscape,
+}
+
+pub struct Terminal<S> {
+    sink: S,
+    column: u16,
+    saved_column: u16,
+    width: u16,
+    height: u16,
+    wrap: Wrap,
+    /// pass SGR (color and style) sequences through, off for sinks that would print them literally
+    colors: bool,
+    /// turn a `\n` that isn't preceded by `\r` into `\r\n`
+    crlf: bool,
+    last: u8,
+    state: State,
+    sequence: [u8; MAX_SEQUENCE],
+    sequence_len: usize,
+    out: [u8; 64],
+    out_len: usize,
+}
+
+impl<S: Sink> Terminal<S> {
+    pub const fn new(sink: S) -> Self {
+        Self {
+            sink,
+            column: 0,
+            saved_column: 0,
+            width: 80,
+            height: 24,
+            wrap: Wrap::Width,
+            colors: true,
+            crlf: true,
+            last: 0,
+            state: State::Ground,
+            sequence: [0; MAX_SEQUENCE],
+            sequence_len: 0,
+            out: [0; 64],
+            out_len: 0,
+        }
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn column(&self) -> u16 {
+        self.column
+    }
+
+    /// columns and rows
+    pub fn size(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    pub fn set_size(&mut self, columns: u16, rows: u16) {
+        self.width = columns.max(1);
+        self.height = rows.max(1);
+    }
+
+    pub fn wrap(&self) -> Wrap {
+        self.wrap
+    }
+
+    pub fn set_wrap(&mut self, wrap: Wrap) {
+        self.wrap = wrap;
+    }
+
+    pub fn colors(&self) -> bool {
+        self.colors
+    }
+
+    pub fn set_colors(&mut self, colors: bool) {
+        self.colors = colors;
+    }
+
+    pub fn set_crlf(&mut self, crlf: bool) {
+        self.crlf = crlf;
+    }
+
+    /// forget where we think the cursor is, for when something else wrote to the sink
+    pub fn reset(&mut self) {
+        self.flush();
+        self.column = 0;
+        self.state = State::Ground;
+        self.sequence_len = 0;
+    }
+
+    fn wrap_width(&self) -> Option<u16> {
+        match self.wrap {
+            Wrap::Off => None,
+            Wrap::At(columns) => Some(columns.max(1)),
+            Wrap::Width => Some(self.width),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.out_len == self.out.len() {
+                self.flush();
+            }
+            self.out[self.out_len] = byte;
+            self.out_len += 1;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.out_len > 0 {
+            self.sink.write_bytes(&self.out[..self.out_len]);
+            self.out_len = 0;
+        }
+    }
+
+    /// a byte of text, not part of an escape sequence
+    fn text(&mut self, byte: u8, wrap: bool) {
+        match byte {
+            ESC => {
+                self.state = State::Escape;
+                self.sequence[0] = ESC;
+                self.sequence_len = 1;
+                return;
+            }
+            b'\r' => {
+                self.column = 0;
+                self.emit(b"\r");
+            }
+            b'\n' => {
+                if self.crlf && self.last != b'\r' {
+                    self.emit(b"\r");
+                }
+                self.column = 0;
+                self.emit(b"\n");
+            }
+            b'\t' => {
+                let next = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                match self.wrap_width().filter(|&width| wrap && next >= width) {
+                    Some(_) => {
+                        self.emit(b"\r\n");
+                        self.column = 0;
+                    }
+                    None => {
+                        self.emit(b"\t");
+                        self.column = next;
+                    }
+                }
+            }
+            0x08 => {
+                self.column = self.column.saturating_sub(1);
+                self.emit(&[byte]);
+            }
+            // other control characters and UTF-8 continuation bytes don't take up a column
+            0x00..=0x1f | 0x7f | 0x80..=0xbf => self.emit(&[byte]),
+            _ => {
+                if let Some(width) = self.wrap_width().filter(|_| wrap) {
+                    if self.column >= width {
+                        self.emit(b"\r\n");
+                        self.column = 0;
+                    }
+                }
+                self.emit(&[byte]);
+                self.column = self.column.saturating_add(1);
+            }
+        }
+        self.last = byte;
+    }
+
+    /// the numeric parameters of the CSI sequence being collected, missing ones are 0
+    fn csi_params(&self) -> [u16; 2] {
+        let mut params = [0u16; 2];
+        let mut index = 0;
+        for &byte in &self.sequence[2..self.sequence_len - 1] {
+            match byte {
+                b'0'..=b'9' if index < params.len() => {
+                    params[index] = params[index]
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16)
+                }
+                b';' => index += 1,
+                _ => {}
+            }
+        }
+        params
+    }
+
+    /// a complete CSI sequence, moves the cursor if it's one that does
+    fn csi(&mut self, last: u8) {
+        let [first, second] = self.csi_params();
+        let count = first.max(1);
+        match last {
+            b'C' => self.column = self.column.saturating_add(count),
+            b'D' => self.column = self.column.saturating_sub(count),
+            b'G' => self.column = count - 1,
+            b'H' | b'f' => self.column = second.max(1) - 1,
+            b'E' | b'F' => self.column = 0,
+            _ => {}
+        }
+        let sequence = self.sequence;
+        if last != b'm' || self.colors {
+            self.emit(&sequence[..self.sequence_len]);
+        }
+    }
+
+    fn sequence_byte(&mut self, byte: u8) {
+        if self.sequence_len == MAX_SEQUENCE {
+            // not something we understand, let it through as is
+            let sequence = self.sequence;
+            self.emit(&sequence);
+            self.emit(&[byte]);
+            self.state = State::Ground;
+            return;
+        }
+        self.sequence[self.sequence_len] = byte;
+        self.sequence_len += 1;
+        let sequence = self.sequence;
+        let whole = &sequence[..self.sequence_len];
+        match (self.state, byte) {
+            (State::Escape, b'[') => self.state = State::Csi,
+            (State::Escape, b']') => self.state = State::Osc,
+            (State::Escape, _) => {
+                match byte {
+                    b'7' => self.saved_column = self.column,
+                    b'8' => self.column = self.saved_column,
+                    b'c' => self.column = 0,
+                    _ => {}
+                }
+                self.emit(whole);
+                self.state = State::Ground;
+            }
+            (State::Csi, 0x40..=0x7e) => {
+                self.csi(byte);
+                self.state = State::Ground;
+            }
+            (State::Osc, BEL) | (State::OscEscape, b'\\') => {
+                self.emit(whole);
+                self.state = State::Ground;
+            }
+            (State::Osc, ESC) => self.state = State::OscEscape,
+            (State::OscEscape, _) => self.state = State::Osc,
+            _ => {}
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8], wrap: bool) {
+        for &byte in bytes {
+            if self.state == State::Ground {
+                self.text(byte, wrap);
+            } else {
+                self.sequence_byte(byte);
+            }
+        }
+        self.flush();
+    }
+
+    /// write `s` keeping track of the cursor, but never wrapping. for drawing with escape sequences
+    pub fn write_raw(&mut self, s: &str) {
+        self.feed(s.as_bytes(), false);
+    }
+
+    /// the query for the terminal size: save the cursor, move it as far as it goes, ask where it
+    /// ended up and put it back. the answer comes in on the input side, see `read_size_reply`
+    pub fn request_size(&mut self) {
+        self.write_raw("\x1b7\x1b[999;999H\x1b[6n\x1b8");
+    }
+}
+
+impl<S: Sink> fmt::Write for Terminal<S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.feed(s.as_bytes(), true);
+        Ok(())
+    }
+}
+
+/// read the `ESC [ rows ; columns R` answer to `request_size`, giving up after `timeout`.
+/// whatever else was typed in the meantime is lost
+pub fn read_size_reply(
+    mut read: impl FnMut() -> Option<u8>,
+    timeout: Duration,
+) -> Option<(u16, u16)> {
+    let timeout = Timeout::after(timeout);
+    let mut next = || time::wait_until(Some(timeout), &mut read);
+    // skip to the start of the reply
+    while next()? != ESC {}
+    if next()? != b'[' {
+        return None;
+    }
+    let mut params = [0u16; 2];
+    let mut index = 0;
+    loop {
+        match next()? {
+            byte @ b'0'..=b'9' if index < params.len() => {
+                params[index] = params[index]
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16)
+            }
+            b';' => index += 1,
+            b'R' => break,
+            _ => return None,
+        }
+    }
+    let [rows, columns] = params;
+    (rows > 0 && columns > 0).then_some((columns, rows))
+}
+
+/// the 16 standard ANSI colors
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+    BrightBlack,
+    BrightRed,
+    BrightGreen,
+    BrightYellow,
+    BrightBlue,
+    BrightMagenta,
+    BrightCyan,
+    BrightWhite,
+}
+
+impl Color {
+    fn foreground(self) -> u8 {
+        match self as u8 {
+            index @ 0..=7 => 30 + index,
+            index => 90 + index - 8,
+        }
+    }
+
+    fn background(self) -> u8 {
+        self.foreground() + 10
+    }
+}
+
+/// colors and attributes for a piece of text, `Display` writes the SGR sequence that turns them on
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Style {
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    pub const fn new() -> Self {
+        Self {
+            foreground: None,
+            background: None,
+            bold: false,
+            dim: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+
+    pub const fn fg(self, color: Color) -> Self {
+        Self {
+            foreground: Some(color),
+            ..self
+        }
+    }
+
+    pub const fn bg(self, color: Color) -> Self {
+        Self {
+            background: Some(color),
+            ..self
+        }
+    }
+
+    pub const fn bold(self) -> Self {
+        Self { bold: true, ..self }
+    }
+
+    pub const fn dim(self) -> Self {
+        Self { dim: true, ..self }
+    }
+
+    pub const fn underline(self) -> Self {
+        Self {
+            underline: true,
+            ..self
+        }
+    }
+
+    pub const fn reverse(self) -> Self {
+        Self {
+            reverse: true,
+            ..self
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        *self == Self::new()
+    }
+
+    /// `value` in this style, then back to normal
+    pub fn paint<T: fmt::Display>(self, value: T) -> Styled<T> {
+        Styled { style: self, value }
+    }
+}
+
+impl fmt::Display for Style {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_plain() {
+            return Ok(());
+        }
+        f.write_str("\x1b[0")?;
+        let flags = [
+            (self.bold, 1),
+            (self.dim, 2),
+            (self.underline, 4),
+            (self.reverse, 7),
+        ];
+        for (_, code) in flags.iter().filter(|(on, _)| *on) {
+            write!(f, ";{}", code)?;
+        }
+        if let Some(color) = self.foreground {
+            write!(f, ";{}", color.foreground())?;
+        }
+        if let Some(color) = self.background {
+            write!(f, ";{}", color.background())?;
+        }
+        f.write_str("m")
+    }
+}
+
+/// the SGR sequence that turns every style off
+pub const RESET: &str = "\x1b[0m";
+
+/// something displayed in a `Style`
+pub struct Styled<T> {
+    style: Style,
+    value: T,
+}
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.style.is_plain() {
+            return self.value.fmt(f);
+        }
+        write!(f, "{}{}{}", self.style, self.value, RESET)
+    }
+}
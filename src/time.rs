@@ -0,0 +1,236 @@
+// time.rs
+// monotonic clock and one-shot timer interrupts, from the CLINT in M-mode or the SBI in S-mode
+#![allow(dead_code)]
+use crate::{
+    clint,
+    csr::{self, Mode},
+    csr_read, sbi, smp,
+};
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use fdt::Fdt;
+use log::*;
+
+/// QEMU virt's timebase, used if the device tree doesn't say
+const DEFAULT_TIMEBASE: u64 = 10_000_000;
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static TIMEBASE: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE);
+
+/// a point on the monotonic clock, in timer ticks since reset
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(ticks())
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// time since `earlier`, zero if `earlier` is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Self(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_boot = ticks_to_duration(self.0);
+        write!(
+            f,
+            "{}.{:06}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros()
+        )
+    }
+}
+
+/// a deadline that something can give up waiting at
+#[derive(Copy, Clone, Debug)]
+pub struct Timeout {
+    deadline: Instant,
+}
+
+impl Timeout {
+    pub fn after(duration: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + duration,
+        }
+    }
+
+    pub fn at(deadline: Instant) -> Self {
+        Self { deadline }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// make sure a timer interrupt wakes a `wfi` at the deadline
+    pub fn arm(&self) {
+        arm(self.deadline)
+    }
+}
+
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * TIMEBASE.load(Ordering::Relaxed) as u128 / NANOS_PER_SEC;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / TIMEBASE.load(Ordering::Relaxed) as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// timer ticks per second
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE.load(Ordering::Relaxed)
+}
+
+/// the raw tick counter
+pub fn ticks() -> u64 {
+    // without a CLINT (S-mode, the firmware owns it) the `time` csr reads the same counter
+    clint::mtime().unwrap_or_else(|| csr_read!("time") as u64)
+}
+
+/// time since the counter started, close enough to time since boot
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// program the calling hart's comparator, `u64::MAX` turns it off
+fn program(deadline: u64) {
+    if clint::base().is_some() {
+        clint::set_mtimecmp(smp::hart_id(), deadline);
+    } else {
+        sbi::set_timer(deadline);
+    }
+}
+
+/// ask for a timer interrupt at `deadline`, unless an earlier one is already programmed
+pub fn arm(deadline: Instant) {
+    let was_enabled = csr::disable_interrupts();
+    let next = &smp::current_hart().timer_deadline;
+    if deadline.0 < next.load(Ordering::Relaxed) {
+        next.store(deadline.0, Ordering::Relaxed);
+        program(deadline.0);
+    }
+    csr::restore_interrupts(was_enabled);
+}
+
+/// called from the trap handler. the timer is one-shot, whoever armed it re-arms if they still need it
+pub fn handle_interrupt() {
+    smp::current_hart()
+        .timer_deadline
+        .store(u64::MAX, Ordering::Relaxed);
+    program(u64::MAX);
+}
+
+/// sleep until `poll` returns something or the timeout passes
+pub fn wait_until<T>(timeout: Option<Timeout>, mut poll: impl FnMut() -> Option<T>) -> Option<T> {
+    loop {
+        let was_enabled = csr::disable_interrupts();
+        if let Some(value) = poll() {
+            csr::restore_interrupts(was_enabled);
+            return Some(value);
+        }
+        if timeout.map_or(false, |timeout| timeout.expired()) {
+            csr::restore_interrupts(was_enabled);
+            return None;
+        }
+        if was_enabled {
+            if let Some(timeout) = timeout {
+                timeout.arm();
+            }
+            csr::wfi();
+        }
+        csr::restore_interrupts(was_enabled);
+    }
+}
+
+/// block the cpu for at least `duration`, idling in `wfi` while we wait
+pub fn sleep(duration: Duration) {
+    let timeout = Timeout::after(duration);
+    wait_until(Some(timeout), || None::<()>);
+}
+
+/// turn the timer off and enable its interrupt on the calling hart
+pub fn init_hart() {
+    // mtimecmp resets to 0, so push it out before letting the interrupt through
+    program(u64::MAX);
+    match csr::mode() {
+        Mode::Machine if clint::base().is_some() => {
+            csr::enable_interrupt_sources(csr::IE_TIMER_MACHINE)
+        }
+        Mode::Machine => {}
+        Mode::Supervisor => csr::enable_interrupt_sources(csr::IE_TIMER_SUPERVISOR),
+    }
+}
+
+/// read the timebase from `/cpus`, find the CLINT if we are in M-mode and enable timer interrupts
+pub fn init(fdt: &Fdt) {
+    if let Some(freq) = fdt
+        .find_node("/cpus")
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|prop| prop.as_usize())
+    {
+        TIMEBASE.store(freq as u64, Ordering::Relaxed);
+    } else {
+        warn!(
+            "time: no timebase-frequency in /cpus, assuming {}Hz",
+            DEFAULT_TIMEBASE
+        );
+    }
+    if csr::mode() == Mode::Machine && clint::base().is_none() && !clint::init(fdt) {
+        warn!("time: no CLINT in the device tree, timer interrupts are unavailable");
+        return;
+    }
+    init_hart();
+    info!(
+        "time: timebase {}Hz, uptime {:?}",
+        timebase_frequency(),
+        Instant::now()
+    );
+}
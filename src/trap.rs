@@ -0,0 +1,423 @@
+// trap.rs
+// trap entry, register frame and the exception/interrupt dispatcher
+use crate::{
+    csr::{self, Mode},
+    executor, layout, plic, println, sched, smp, time,
+};
+use core::{arch::global_asm, fmt, sync::atomic::Ordering};
+
+/// the registers saved by the trap vector, laid out exactly as the assembly below stores them
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct TrapFrame {
+    /// general purpose registers, indexed by register number (`regs[0]` is always 0)
+    pub regs: [usize; 32],
+    /// `mepc`/`sepc`, where to return to
+    pub pc: usize,
+    /// `mstatus`/`sstatus` at the time of the trap
+    pub status: usize,
+    /// `mcause`/`scause`
+    pub cause: usize,
+    /// `mtval`/`stval`, the faulting address or instruction
+    pub tval: usize,
+}
+
+/// size of the `TrapFrame` in bytes, must stay in sync with the assembly
+const FRAME_SIZE: usize = core::mem::size_of::<TrapFrame>();
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pc: {:#018x} status: {:#018x}\r\ncause: {:#018x} tval: {:#018x}",
+            self.pc, self.status, self.cause, self.tval
+        )?;
+        for (i, (name, value)) in REGISTER_NAMES.iter().zip(self.regs.iter()).enumerate() {
+            if i % 4 == 0 {
+                write!(f, "\r\n")?;
+            }
+            write!(f, "{:>4}: {:#018x} ", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// synchronous traps, numbered as in `mcause`/`scause`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+    UserEnvCall,
+    SupervisorEnvCall,
+    MachineEnvCall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+    Unknown(usize),
+}
+
+impl From<usize> for Exception {
+    fn from(code: usize) -> Self {
+        match code {
+            0 => Self::InstructionMisaligned,
+            1 => Self::InstructionAccessFault,
+            2 => Self::IllegalInstruction,
+            3 => Self::Breakpoint,
+            4 => Self::LoadMisaligned,
+            5 => Self::LoadAccessFault,
+            6 => Self::StoreMisaligned,
+            7 => Self::StoreAccessFault,
+            8 => Self::UserEnvCall,
+            9 => Self::SupervisorEnvCall,
+            11 => Self::MachineEnvCall,
+            12 => Self::InstructionPageFault,
+            13 => Self::LoadPageFault,
+            15 => Self::StorePageFault,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+/// asynchronous traps, numbered as in `mcause`/`scause` with the top bit cleared
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+    Unknown(usize),
+}
+
+impl From<usize> for Interrupt {
+    fn from(code: usize) -> Self {
+        match code {
+            1 => Self::SupervisorSoftware,
+            3 => Self::MachineSoftware,
+            5 => Self::SupervisorTimer,
+            7 => Self::MachineTimer,
+            9 => Self::SupervisorExternal,
+            11 => Self::MachineExternal,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+/// a decoded `mcause`/`scause`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trap {
+    Interrupt(Interrupt),
+    Exception(Exception),
+}
+
+impl Trap {
+    pub fn from_cause(cause: usize) -> Self {
+        let interrupt_bit = 1 << (usize::BITS - 1);
+        let code = cause & !interrupt_bit;
+        if cause & interrupt_bit != 0 {
+            Self::Interrupt(Interrupt::from(code))
+        } else {
+            Self::Exception(Exception::from(code))
+        }
+    }
+}
+
+/// the first bytes of each trap stack hold t0..t2 while the vector decides where the frame goes
+const TRAP_STACK_SCRATCH: usize = 32;
+
+// one trap vector per privilege mode, they only differ in which csrs they touch.
+// the frame is pushed onto the interrupted stack, sp is stored as it was before the trap.
+// the handler may switch tasks, so the frame has to live on the task's own stack. the one
+// exception is a stack with no room left for it: pushing the frame into the guard page would
+// fault again and again, walking sp down into whatever is mapped below. `mscratch`/`sscratch`
+// hold the top of this hart's trap stack, which gives the vector somewhere to check that from
+// and, if it comes to it, somewhere to put the frame so the handler can report the overflow
+macro_rules! trap_vector {
+    ($name:literal, $p:literal) => {
+        global_asm!(
+            ".section .text",
+            ".align 4",
+            concat!(".global ", $name),
+            concat!($name, ":"),
+            concat!("csrrw sp, ", $p, "scratch, sp"),
+            "sd t0, -8(sp)",
+            "sd t1, -16(sp)",
+            "sd t2, -24(sp)",
+            concat!("csrr t0, ", $p, "scratch"),
+            // would the frame reach into the guard page below the init stack
+            "la t1, _init_stack_guard",
+            "sub t1, t0, t1",
+            "addi t1, t1, -1",
+            "li t2, {guard} + {size} - 1",
+            "bltu t1, t2, 1f",
+            // or the one below any of the hart stacks
+            "la t1, _hart_stacks_start",
+            "sub t1, t0, t1",
+            "li t2, {hart_stacks_size}",
+            "bgeu t1, t2, 6f",
+            // the offset into its slot, the global asm doesn't get the M extension for remu
+            "li t2, {hart_slot_size}",
+            "4:",
+            "bltu t1, t2, 5f",
+            "sub t1, t1, t2",
+            "j 4b",
+            "5:",
+            "addi t1, t1, -1",
+            "li t2, {guard} + {size} - 1",
+            "bltu t1, t2, 1f",
+            "j 2f",
+            // or the one below a task stack, which is anywhere outside the kernel image
+            "6:",
+            "addi t2, t0, -1",
+            "la t1, _kernel_start",
+            "bltu t2, t1, 7f",
+            "la t1, end",
+            "bltu t2, t1, 2f",
+            // a task's guard sits at a fixed offset into its slot, see sched.rs
+            "7:",
+            "li t1, {task_slot_size} - 1",
+            "and t1, t1, t2",
+            "li t2, {task_stack_size} - {guard}",
+            "sub t1, t1, t2",
+            "li t2, {guard} + {size} - 1",
+            "bltu t1, t2, 1f",
+            // there's room, back to the interrupted stack
+            "2:",
+            "ld t2, -24(sp)",
+            "ld t1, -16(sp)",
+            "ld t0, -8(sp)",
+            concat!("csrrw sp, ", $p, "scratch, sp"),
+            "addi sp, sp, -{size}",
+            "sd x5, 5*8(sp)",
+            "addi t0, sp, {size}",
+            "sd t0, 2*8(sp)",
+            "j 3f",
+            // no room, the frame goes on the trap stack and the handler won't come back
+            "1:",
+            "ld t2, -24(sp)",
+            "ld t1, -16(sp)",
+            "ld t0, -8(sp)",
+            "addi sp, sp, -{size} - {scratch}",
+            "sd x5, 5*8(sp)",
+            concat!("csrr t0, ", $p, "scratch"),
+            "sd t0, 2*8(sp)",
+            // anything taken while reporting it starts from the top of the trap stack again
+            "addi t0, sp, {size} + {scratch}",
+            concat!("csrw ", $p, "scratch, t0"),
+            "3:",
+            "sd x1, 1*8(sp)",
+            "sd x3, 3*8(sp)",
+            "sd x4, 4*8(sp)",
+            "sd x6, 6*8(sp)",
+            "sd x7, 7*8(sp)",
+            "sd x8, 8*8(sp)",
+            "sd x9, 9*8(sp)",
+            "sd x10, 10*8(sp)",
+            "sd x11, 11*8(sp)",
+            "sd x12, 12*8(sp)",
+            "sd x13, 13*8(sp)",
+            "sd x14, 14*8(sp)",
+            "sd x15, 15*8(sp)",
+            "sd x16, 16*8(sp)",
+            "sd x17, 17*8(sp)",
+            "sd x18, 18*8(sp)",
+            "sd x19, 19*8(sp)",
+            "sd x20, 20*8(sp)",
+            "sd x21, 21*8(sp)",
+            "sd x22, 22*8(sp)",
+            "sd x23, 23*8(sp)",
+            "sd x24, 24*8(sp)",
+            "sd x25, 25*8(sp)",
+            "sd x26, 26*8(sp)",
+            "sd x27, 27*8(sp)",
+            "sd x28, 28*8(sp)",
+            "sd x29, 29*8(sp)",
+            "sd x30, 30*8(sp)",
+            "sd x31, 31*8(sp)",
+            concat!("csrr t0, ", $p, "epc"),
+            "sd t0, 32*8(sp)",
+            concat!("csrr t0, ", $p, "status"),
+            "sd t0, 33*8(sp)",
+            concat!("csrr t0, ", $p, "cause"),
+            "sd t0, 34*8(sp)",
+            concat!("csrr t0, ", $p, "tval"),
+            "sd t0, 35*8(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            // the handler may have changed where we return to
+            "ld t0, 32*8(sp)",
+            concat!("csrw ", $p, "epc, t0"),
+            "ld t0, 33*8(sp)",
+            concat!("csrw ", $p, "status, t0"),
+            "ld x1, 1*8(sp)",
+            "ld x3, 3*8(sp)",
+            "ld x4, 4*8(sp)",
+            "ld x5, 5*8(sp)",
+            "ld x6, 6*8(sp)",
+            "ld x7, 7*8(sp)",
+            "ld x8, 8*8(sp)",
+            "ld x9, 9*8(sp)",
+            "ld x10, 10*8(sp)",
+            "ld x11, 11*8(sp)",
+            "ld x12, 12*8(sp)",
+            "ld x13, 13*8(sp)",
+            "ld x14, 14*8(sp)",
+            "ld x15, 15*8(sp)",
+            "ld x16, 16*8(sp)",
+            "ld x17, 17*8(sp)",
+            "ld x18, 18*8(sp)",
+            "ld x19, 19*8(sp)",
+            "ld x20, 20*8(sp)",
+            "ld x21, 21*8(sp)",
+            "ld x22, 22*8(sp)",
+            "ld x23, 23*8(sp)",
+            "ld x24, 24*8(sp)",
+            "ld x25, 25*8(sp)",
+            "ld x26, 26*8(sp)",
+            "ld x27, 27*8(sp)",
+            "ld x28, 28*8(sp)",
+            "ld x29, 29*8(sp)",
+            "ld x30, 30*8(sp)",
+            "ld x31, 31*8(sp)",
+            "addi sp, sp, {size}",
+            concat!($p, "ret"),
+            size = const FRAME_SIZE,
+            scratch = const TRAP_STACK_SCRATCH,
+            guard = const layout::GUARD_SIZE,
+            hart_stacks_size = const layout::MAX_HARTS * (layout::GUARD_SIZE + layout::HART_STACK_SIZE),
+            hart_slot_size = const layout::GUARD_SIZE + layout::HART_STACK_SIZE,
+            task_stack_size = const sched::STACK_SIZE,
+            task_slot_size = const sched::STACK_SLOT_SIZE,
+            handler = sym trap_handler,
+        );
+    };
+}
+
+trap_vector!("machine_trap_vector", "m");
+trap_vector!("supervisor_trap_vector", "s");
+
+extern "C" {
+    fn machine_trap_vector();
+    fn supervisor_trap_vector();
+}
+
+/// install the trap vector for the mode we are running in and enable external and software
+/// interrupts. external interrupts are only taken once something is enabled in the plic
+pub fn init() {
+    csr::detect_mode();
+    init_hart();
+}
+
+/// the same for a hart other than the boot hart, once `init` has found the mode
+pub fn init_hart() {
+    let trap_stack_top = smp::current_hart().trap_stack_top();
+    assert_ne!(trap_stack_top, 0, "trap: no trap stack for this hart");
+    csr::set_trap_scratch(trap_stack_top);
+    match csr::mode() {
+        Mode::Machine => {
+            csr::set_trap_vector(machine_trap_vector as usize);
+            csr::enable_interrupt_sources(csr::IE_EXTERNAL_MACHINE | csr::IE_SOFTWARE_MACHINE);
+        }
+        Mode::Supervisor => {
+            csr::set_trap_vector(supervisor_trap_vector as usize);
+            csr::enable_interrupt_sources(
+                csr::IE_EXTERNAL_SUPERVISOR | csr::IE_SOFTWARE_SUPERVISOR,
+            );
+        }
+    }
+    csr::enable_interrupts();
+}
+
+/// length of the instruction at `pc`, compressed instructions don't have the low bits set
+fn instruction_length(pc: usize) -> usize {
+    let low = unsafe { (pc as *const u16).read_volatile() };
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// note the frame a panic is about to be raised for, so the panic handler can dump it
+fn set_panic_frame(frame: &TrapFrame) {
+    if smp::try_hart_id().is_some() {
+        let frame = frame as *const TrapFrame as usize;
+        smp::current_hart()
+            .panic_frame
+            .store(frame, Ordering::Relaxed);
+    }
+}
+
+/// the frame of the trap the calling hart's panic came from, if it came from one.
+/// it sits further up the same stack as the panic handler, so it's still intact
+pub fn take_panic_frame() -> Option<&'static TrapFrame> {
+    smp::try_hart_id()?;
+    let frame = smp::current_hart().panic_frame.swap(0, Ordering::Relaxed);
+    (frame != 0).then(|| unsafe { &*(frame as *const TrapFrame) })
+}
+
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    let trap = Trap::from_cause(frame.cause);
+    let page_fault = matches!(
+        trap,
+        Trap::Exception(
+            Exception::InstructionPageFault | Exception::LoadPageFault | Exception::StorePageFault
+        )
+    );
+    // the vector only puts the frame on the trap stack when the interrupted stack was out of room
+    if !page_fault && layout::trap_stacks().contains(frame as *const TrapFrame as usize) {
+        set_panic_frame(frame);
+        panic!(
+            "kernel stack overflow, {:?} with sp {:#x} at {:#x}",
+            trap, frame.regs[2], frame.pc
+        );
+    }
+    match trap {
+        Trap::Interrupt(Interrupt::MachineExternal | Interrupt::SupervisorExternal) => {
+            plic::handle_interrupt();
+        }
+        Trap::Interrupt(Interrupt::MachineTimer | Interrupt::SupervisorTimer) => {
+            time::handle_interrupt();
+            executor::wake_timers();
+            sched::tick();
+        }
+        Trap::Interrupt(Interrupt::MachineSoftware | Interrupt::SupervisorSoftware) => {
+            smp::handle_ipi();
+            sched::tick();
+        }
+        Trap::Exception(Exception::Breakpoint) => {
+            // step over the `ebreak` so debug builds can keep going without a debugger attached
+            println!("breakpoint at {:#x}", frame.pc);
+            frame.pc += instruction_length(frame.pc);
+        }
+        trap if page_fault => {
+            set_panic_frame(frame);
+            let guarded =
+                layout::guarded_by(frame.tval).or_else(|| sched::task_stack_guarded_by(frame.tval));
+            match guarded {
+                Some(region) => panic!(
+                    "hit the guard page below the {} at {:#x}, pc {:#x}",
+                    region.name, frame.tval, frame.pc
+                ),
+                None => panic!("unhandled trap {:?} at {:#x}", trap, frame.pc),
+            }
+        }
+        trap => {
+            set_panic_frame(frame);
+            panic!("unhandled trap {:?} at {:#x}", trap, frame.pc);
+        }
+    }
+}
@@ -1,37 +1,139 @@
-use core::fmt::Write;
-use uart_16550::MmioSerialPort;
-
-pub static mut TERM: Option<MmioSerialPort> = None;
-static mut X_POS: u8 = 0;
-pub fn init_from_mmio(addr: usize) {
-    unsafe {
-        TERM = Some(MmioSerialPort::new(addr));
-        TERM.as_mut().unwrap().init();
-        X_POS = 0;
+#![allow(dead_code)]
+use crate::{
+    cmdline, ns16550,
+    platform::{self, Console},
+    sbi,
+    sync::TicketLock,
+    term::{self, Sink, Terminal, Wrap},
+    time,
+};
+use core::{fmt::Write, time::Duration};
+use fdt::Fdt;
+use log::*;
+
+/// how long to wait for the terminal to answer a size query
+const SIZE_QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// the console output, whichever device `platform` picked
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        write_all(bytes)
     }
 }
 
-struct MmioSerialWithXPos<'a> {
-    ser: &'a mut MmioSerialPort,
+/// a ticket lock so harts printing at the same time take turns line by line
+pub static TERM: TicketLock<Terminal<ConsoleSink>> =
+    TicketLock::new("uart", Terminal::new(ConsoleSink));
+
+/// polled output to the 16550 at `addr` until `init_irq` hooks up its interrupt
+pub fn init_from_mmio(addr: usize, reg_shift: usize) {
+    ns16550::init(addr, reg_shift);
+    TERM.lock().reset();
 }
 
-impl Write for MmioSerialWithXPos<'_> {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        unsafe {
-            for char in s.bytes() {
-                if (X_POS >= 80) || (char == b'\n') {
-                    X_POS = 0;
-                    if char != b'\n' {
-                        self.ser.send(b'\r');
-                        self.ser.send(b'\n');
-                    }
-                    self.ser.send(char)
-                } else {
-                    self.ser.send(char);
-                    X_POS += 1
-                }
+/// take console input and output through the UART interrupt from now on, needs the PLIC
+pub fn init_irq(fdt: &Fdt) -> bool {
+    platform::console() == Console::Uart && ns16550::enable_interrupts(fdt)
+}
+
+/// queue as much of `bytes` as the console takes without waiting, returns how many it took
+pub fn write(bytes: &[u8]) -> usize {
+    match platform::console() {
+        Console::Uart => ns16550::port().map_or(0, |port| port.write_bytes(bytes)),
+        // the SBI calls don't return until the byte is out anyway
+        Console::SbiDebug | Console::SbiLegacy => {
+            write_all(bytes);
+            bytes.len()
+        }
+    }
+}
+
+/// send all of `bytes` to whichever console `platform` picked, waiting for room if needed
+fn write_all(bytes: &[u8]) {
+    match platform::console() {
+        Console::Uart => {
+            if let Some(port) = ns16550::port() {
+                port.write_all(bytes)
             }
-            Ok(())
+        }
+        Console::SbiDebug => {
+            for &byte in bytes {
+                sbi::console_write_byte(byte);
+            }
+        }
+        Console::SbiLegacy => {
+            for &byte in bytes {
+                sbi::legacy_putchar(byte)
+            }
+        }
+    }
+}
+
+/// a byte from whichever console `platform` picked, if one is waiting
+pub fn try_read() -> Option<u8> {
+    match platform::console() {
+        Console::Uart => ns16550::port()?.try_read(),
+        Console::SbiDebug => {
+            let mut byte = [0];
+            match sbi::console_read(&mut byte) {
+                Ok(1) => Some(byte[0]),
+                _ => None,
+            }
+        }
+        Console::SbiLegacy => sbi::legacy_getchar(),
+    }
+}
+
+/// wait for a byte from whichever console `platform` picked. with the UART interrupt
+/// the hart sleeps until something arrives, otherwise nothing would wake it so we poll
+pub fn receive() -> u8 {
+    let interrupt_driven = platform::console() == Console::Uart
+        && ns16550::port().map_or(false, |port| port.irq().is_some());
+    if interrupt_driven {
+        return time::wait_until(None, try_read).unwrap();
+    }
+    loop {
+        if let Some(byte) = try_read() {
+            return byte;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// how the console wraps long lines
+pub fn set_wrap(wrap: Wrap) {
+    TERM.lock().set_wrap(wrap)
+}
+
+/// whether color and style sequences reach the console or get dropped
+pub fn set_colors(colors: bool) {
+    TERM.lock().set_colors(colors)
+}
+
+/// ask the terminal on the console how big it is and wrap at that width from now on.
+/// `None` if it didn't answer, the SBI consoles and plain serial logs usually don't
+pub fn query_size() -> Option<(u16, u16)> {
+    TERM.lock().request_size();
+    // the answer comes in through the UART interrupt, so it can't be waited for under `TERM`
+    let (columns, rows) = term::read_size_reply(try_read, SIZE_QUERY_TIMEOUT)?;
+    TERM.lock().set_size(columns, rows);
+    Some((columns, rows))
+}
+
+/// apply `term.wrap=` and `term.colors=`. colors default to on only for the UART, the SBI consoles
+/// tend to end up in logs. wrapping at the terminal's width asks the terminal how wide it is, which
+/// only a UART has anyone to answer. needs the timer for the query's timeout
+pub fn configure_from_cmdline() {
+    let uart = platform::console() == Console::Uart;
+    set_colors(cmdline::term_colors().unwrap_or(uart));
+    let wrap = cmdline::term_wrap().unwrap_or(Wrap::Width);
+    set_wrap(wrap);
+    if wrap == Wrap::Width && uart {
+        match query_size() {
+            Some((columns, rows)) => info!("uart: terminal is {}x{}", columns, rows),
+            None => info!("uart: terminal didn't say how big it is"),
         }
     }
 }
@@ -47,38 +149,44 @@ macro_rules! println {
     ($($t:tt)*) => { $crate::uart::print_fmt(format_args!("{}\r\n", format_args!($($t)*))) };
 }
 
+/// read a line from the console with editing and history, see `line_edit`
 #[macro_export]
 macro_rules! readln {
-    () => {{
-        let mut _lastchar = b'\x00';
-        let mut output = alloc::string::String::new();
-        let _term = $crate::uart::TERM.as_mut().unwrap();
-        while _lastchar != b'\r' {
-            _lastchar = _term.receive();
-            output.push(_lastchar as char);
-            print!("{}", _lastchar as char);
-        }
-        println!();
-        output
-    }};
+    () => {
+        $crate::line_edit::read_line()
+    };
 }
 
 pub fn print_fmt(args: core::fmt::Arguments) {
-    if let Some(term) = unsafe { TERM.as_mut() } {
-        let _ = (MmioSerialWithXPos { ser: term }).write_fmt(args);
-    }
+    let _ = TERM.lock().write_fmt(args);
 }
 
-pub struct UartLogger {}
-
-impl log::Log for UartLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
-    }
+/// write `s` out without wrapping, for drawing with escape sequences
+pub fn print_raw(s: &str) {
+    TERM.lock().write_raw(s);
+}
 
-    fn log(&self, record: &log::Record) {
-        println!("{}@{}", record.module_path().unwrap_or("?"), record.args())
+/// print without taking `TERM` or the UART's queue, for reporting a hart stuck on a lock (maybe one
+/// of those). the output can end up in the middle of someone else's line
+pub fn print_unlocked(args: core::fmt::Arguments) {
+    struct Unlocked;
+    impl Write for Unlocked {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            for byte in s.bytes() {
+                match platform::console() {
+                    Console::Uart => {
+                        if let Some(port) = ns16550::port() {
+                            port.write_raw(byte)
+                        }
+                    }
+                    Console::SbiDebug => {
+                        sbi::console_write_byte(byte);
+                    }
+                    Console::SbiLegacy => sbi::legacy_putchar(byte),
+                }
+            }
+            Ok(())
+        }
     }
-
-    fn flush(&self) {}
+    let _ = Unlocked.write_fmt(args);
 }
@@ -0,0 +1,166 @@
+// virtio_async.rs
+// async wrappers around the virtio devices, built on the non-blocking virtio-drivers calls and
+// `executor::wait_irq` so a request in flight doesn't hold up anything else
+#![allow(dead_code)]
+use crate::{executor, virtio_hal::HalImpl, virtio_irq::VirtioIrq};
+use core::{hint::spin_loop, time::Duration};
+use virtio_drivers::{
+    device::{
+        blk::{BlkReq, BlkResp, VirtIOBlk},
+        console::VirtIOConsole,
+    },
+    transport::Transport,
+};
+
+/// the data side of a block request
+enum Buffer<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// a block request the device may still be working on. the device owns `req`, `buf` and `resp`
+/// until it's completed, so if the future is dropped early the drop waits for the device to finish
+struct InFlight<'a, T: Transport> {
+    blk: &'a mut VirtIOBlk<HalImpl, T>,
+    token: u16,
+    req: &'a BlkReq,
+    buf: Buffer<'a>,
+    resp: &'a mut BlkResp,
+    completed: bool,
+}
+
+impl<T: Transport> InFlight<'_, T> {
+    fn is_done(&mut self) -> bool {
+        self.blk.peek_used() == Some(self.token)
+    }
+
+    /// take the request off the used ring, only once `is_done`
+    fn complete(&mut self) -> virtio_drivers::Result {
+        self.completed = true;
+        unsafe {
+            match &mut self.buf {
+                Buffer::Read(buf) => self
+                    .blk
+                    .complete_read_blocks(self.token, self.req, buf, self.resp),
+                Buffer::Write(buf) => self
+                    .blk
+                    .complete_write_blocks(self.token, self.req, buf, self.resp),
+            }
+        }
+    }
+}
+
+impl<T: Transport> Drop for InFlight<'_, T> {
+    fn drop(&mut self) {
+        if !self.completed {
+            while !self.is_done() {
+                spin_loop();
+            }
+            let _ = self.complete();
+        }
+    }
+}
+
+/// a virtio block device driven by interrupts, one request at a time
+pub struct AsyncBlk<T: Transport> {
+    blk: VirtIOBlk<HalImpl, T>,
+    irq: Option<&'static VirtioIrq>,
+}
+
+impl<T: Transport> AsyncBlk<T> {
+    /// without an interrupt the requests are polled every time the executor comes around
+    pub fn new(blk: VirtIOBlk<HalImpl, T>, irq: Option<&'static VirtioIrq>) -> Self {
+        Self { blk, irq }
+    }
+
+    pub fn inner(&mut self) -> &mut VirtIOBlk<HalImpl, T> {
+        &mut self.blk
+    }
+
+    pub fn into_inner(self) -> VirtIOBlk<HalImpl, T> {
+        self.blk
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.blk.capacity()
+    }
+
+    async fn finish(irq: Option<u32>, mut request: InFlight<'_, T>) -> virtio_drivers::Result {
+        executor::wait_irq(irq, || request.is_done().then_some(())).await;
+        request.complete()
+    }
+
+    /// read whole sectors starting at `sector`, `buf` must be a multiple of `SECTOR_SIZE`
+    pub async fn read(&mut self, sector: usize, buf: &mut [u8]) -> virtio_drivers::Result {
+        let irq = self.irq.map(|irq| irq.irq());
+        let mut req = BlkReq::default();
+        let mut resp = BlkResp::default();
+        let token = unsafe { self.blk.read_blocks_nb(sector, &mut req, buf, &mut resp)? };
+        let request = InFlight {
+            blk: &mut self.blk,
+            token,
+            req: &req,
+            buf: Buffer::Read(buf),
+            resp: &mut resp,
+            completed: false,
+        };
+        Self::finish(irq, request).await
+    }
+
+    /// write whole sectors starting at `sector`, `buf` must be a multiple of `SECTOR_SIZE`
+    pub async fn write(&mut self, sector: usize, buf: &[u8]) -> virtio_drivers::Result {
+        let irq = self.irq.map(|irq| irq.irq());
+        let mut req = BlkReq::default();
+        let mut resp = BlkResp::default();
+        let token = unsafe { self.blk.write_blocks_nb(sector, &mut req, buf, &mut resp)? };
+        let request = InFlight {
+            blk: &mut self.blk,
+            token,
+            req: &req,
+            buf: Buffer::Write(buf),
+            resp: &mut resp,
+            completed: false,
+        };
+        Self::finish(irq, request).await
+    }
+}
+
+/// a virtio console whose receive side is driven by interrupts
+pub struct AsyncConsole<T: Transport> {
+    console: VirtIOConsole<HalImpl, T>,
+    irq: Option<&'static VirtioIrq>,
+}
+
+impl<T: Transport> AsyncConsole<T> {
+    /// without an interrupt the receive queue is polled every time the executor comes around
+    pub fn new(console: VirtIOConsole<HalImpl, T>, irq: Option<&'static VirtioIrq>) -> Self {
+        Self { console, irq }
+    }
+
+    pub fn inner(&mut self) -> &mut VirtIOConsole<HalImpl, T> {
+        &mut self.console
+    }
+
+    pub fn into_inner(self) -> VirtIOConsole<HalImpl, T> {
+        self.console
+    }
+
+    /// the next byte from the console
+    pub async fn recv(&mut self) -> virtio_drivers::Result<u8> {
+        let irq = self.irq.map(|irq| irq.irq());
+        let console = &mut self.console;
+        executor::wait_irq(irq, || console.recv(true).transpose()).await
+    }
+
+    /// the next byte from the console, `None` if nothing came within `timeout`
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> virtio_drivers::Result<Option<u8>> {
+        executor::with_timeout(timeout, self.recv())
+            .await
+            .transpose()
+    }
+
+    /// virtio-drivers only has a blocking send, it waits for the device to take the byte
+    pub fn send(&mut self, byte: u8) -> virtio_drivers::Result {
+        self.console.send(byte)
+    }
+}
@@ -1,58 +1,200 @@
-use crate::println;
+use crate::{paging, pci_enum, pmm, sync::IrqSafeSpinlock};
+use alloc::{vec, vec::Vec};
 use core::{
+    fmt,
     ptr::{self, NonNull},
-    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    sync::atomic::{AtomicUsize, Ordering},
 };
+use log::*;
 use virtio_drivers::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
 
-extern "C" {
-    fn end();
-}
-/// a bitfield representing which pages have not been allocated
-pub static OPEN_PAGES: AtomicU64 = AtomicU64::new(0);
 /// the number of allocated pages
-pub static ALLOC_PAGES: AtomicU8 = AtomicU8::new(0);
+pub static ALLOC_PAGES: AtomicUsize = AtomicUsize::new(0);
 
-///currently no special init needs to be done (used to have to init a pointer to the end symbol above)
-pub fn init_virtio_hal() {}
+/// how many pages the DMA pool starts with
+pub const DMA_INITIAL_PAGES: usize = 64;
+/// how far the DMA pool may grow in total
+pub const DMA_MAX_PAGES: usize = 4096;
 
-///generate a mask with the left-most bits being set to 1
-fn generate_mask(n: usize) -> u64 {
-    assert!(n <= 64, "gen_mask: n must be less than or equal to 64");
-    if n == 0 {
-        0
-    } else {
-        u64::MAX << (64 - n)
+/// a physically contiguous block from the frame allocator, handed out first-fit one bit per page
+struct DmaChunk {
+    start: usize,
+    order: usize,
+    /// set bits are allocated pages
+    bitmap: Vec<u64>,
+}
+
+impl DmaChunk {
+    fn pages(&self) -> usize {
+        1 << self.order
+    }
+
+    fn is_set(&self, page: usize) -> bool {
+        self.bitmap[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    fn set_range(&mut self, first: usize, count: usize, allocated: bool) {
+        for page in first..first + count {
+            if allocated {
+                self.bitmap[page / 64] |= 1 << (page % 64);
+            } else {
+                self.bitmap[page / 64] &= !(1 << (page % 64));
+            }
+        }
+    }
+
+    /// first page of a free run of `count` pages
+    fn find(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for page in 0..self.pages() {
+            if self.is_set(page) {
+                run = 0;
+            } else {
+                run += 1;
+                if run == count {
+                    return Some(page + 1 - count);
+                }
+            }
+        }
+        None
+    }
+
+    fn contains(&self, paddr: usize, count: usize) -> bool {
+        paddr >= self.start && paddr + count * PAGE_SIZE <= self.start + self.pages() * PAGE_SIZE
     }
 }
 
-///searches for a section of "pages" size that is all unallocated
-pub fn find_open_pages(pages: usize) -> usize {
-    let pages_bitfield = OPEN_PAGES.load(Ordering::Relaxed);
-    if (pages > 64) || (ALLOC_PAGES.load(Ordering::Relaxed) >= 64) {
-        panic!(
-            "why are more than 64 pages being allocated {}",
-            if pages > 64 { "at once" } else { "" },
-        )
+/// the DMA pool, a list of chunks that grows by asking the frame allocator for more
+struct DmaPool {
+    chunks: Vec<DmaChunk>,
+    /// pages the pool may grow to
+    limit: usize,
+    peak: usize,
+}
+
+impl DmaPool {
+    const fn empty() -> Self {
+        Self {
+            chunks: Vec::new(),
+            limit: 0,
+            peak: 0,
+        }
     }
-    let mask = generate_mask(pages);
-    let mut offset: usize = 0;
-    let mut found: bool = false;
-    for off in 0..64 - pages {
-        let omask = mask >> off;
-        if (pages_bitfield & omask) == 0 {
-            found = true;
-            offset = off;
-            break;
+
+    fn pages(&self) -> usize {
+        self.chunks.iter().map(DmaChunk::pages).sum()
+    }
+
+    /// add a chunk big enough for `count` pages, at least as big as the pool already is but no
+    /// bigger than the frame allocator's largest block.
+    /// false if that would pass the limit or there is no contiguous RAM left
+    fn grow(&mut self, count: usize) -> bool {
+        let pages = self.pages();
+        if count > 1 << pmm::MAX_ORDER {
+            return false;
+        }
+        let order = pmm::order_for(count.max(pages)).min(pmm::MAX_ORDER);
+        if pages + (1 << order) > self.limit {
+            return false;
         }
+        let Some(start) = pmm::alloc(order) else {
+            return false;
+        };
+        // pages are zeroed when they join the pool and again when they are freed
+        unsafe { zero_out_memory(start as *mut u8, PAGE_SIZE << order) };
+        self.chunks.push(DmaChunk {
+            start,
+            order,
+            bitmap: vec![0; ((1 << order) + 63) / 64],
+        });
+        debug!("dma pool grew from {} to {} pages", pages, self.pages());
+        true
+    }
+
+    fn alloc(&mut self, count: usize) -> Option<usize> {
+        let (chunk, first) = loop {
+            let found = self
+                .chunks
+                .iter()
+                .enumerate()
+                .find_map(|(i, chunk)| chunk.find(count).map(|first| (i, first)));
+            if let Some(found) = found {
+                break found;
+            }
+            if !self.grow(count) {
+                return None;
+            }
+        };
+        let chunk = &mut self.chunks[chunk];
+        chunk.set_range(first, count, true);
+        let used = ALLOC_PAGES.fetch_add(count, Ordering::Relaxed) + count;
+        self.peak = self.peak.max(used);
+        Some(chunk.start + first * PAGE_SIZE)
     }
-    if !found {
-        panic!(
-            "unnable to find open slot of size {}, {:64b}",
-            pages, pages_bitfield
+
+    /// false if `paddr` isn't from this pool
+    fn dealloc(&mut self, paddr: usize, count: usize) -> bool {
+        let Some(chunk) = self
+            .chunks
+            .iter_mut()
+            .find(|chunk| chunk.contains(paddr, count))
+        else {
+            return false;
+        };
+        let first = (paddr - chunk.start) / PAGE_SIZE;
+        chunk.set_range(first, count, false);
+        ALLOC_PAGES.fetch_sub(count, Ordering::Relaxed);
+        true
+    }
+}
+
+static DMA_POOL: IrqSafeSpinlock<DmaPool> = IrqSafeSpinlock::new("dma pool", DmaPool::empty());
+
+/// a snapshot of the DMA pool for reporting
+#[derive(Copy, Clone, Debug)]
+pub struct DmaUsage {
+    pub chunks: usize,
+    pub pages: usize,
+    pub limit: usize,
+    pub used: usize,
+    pub peak: usize,
+}
+
+impl fmt::Display for DmaUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "DMA pages: {}/{} used (peak {}, limit {}) in {} chunks",
+            self.used, self.pages, self.peak, self.limit, self.chunks
         )
     }
-    offset
+}
+
+/// report how much of the DMA pool is in use.
+/// uses `try_lock` so it can be called from the panic handler even if the panic happened mid-allocation
+pub fn dma_usage() -> Option<DmaUsage> {
+    let pool = DMA_POOL.try_lock()?;
+    Some(DmaUsage {
+        chunks: pool.chunks.len(),
+        pages: pool.pages(),
+        limit: pool.limit,
+        used: ALLOC_PAGES.load(Ordering::Relaxed),
+        peak: pool.peak,
+    })
+}
+
+///reserve the first chunk of the DMA pool from the frame allocator, it may grow to `max_pages`
+pub fn init_virtio_hal(initial_pages: usize, max_pages: usize) {
+    let mut pool = DMA_POOL.lock();
+    pool.limit = max_pages;
+    if !pool.grow(initial_pages) {
+        warn!("unable to reserve {initial_pages} DMA pages up front");
+    }
+    info!(
+        "dma pool: {} pages, may grow to {}",
+        pool.pages(),
+        pool.limit
+    );
 }
 
 /// used to clear the memory once the page is de-allocated
@@ -70,51 +212,40 @@ unsafe fn zero_out_memory(ptr: *mut u8, count: usize) {
 pub struct HalImpl;
 unsafe impl Hal for HalImpl {
     fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
-        let block_offset = find_open_pages(pages);
-        let dma_block = (end as usize) + (PAGE_SIZE * block_offset);
-        ALLOC_PAGES.fetch_add(pages as u8, Ordering::Relaxed);
-        OPEN_PAGES.fetch_or(generate_mask(pages) >> block_offset, Ordering::Relaxed);
-        let vaddr = NonNull::new(dma_block as _).unwrap();
-        #[cfg(not(feature = "no_log"))]
-        {
-            println!(
-                "alloc@{}?{}: {:064b}",
-                block_offset,
-                pages,
-                OPEN_PAGES.load(Ordering::Relaxed)
-            );
-            println!(
-                "{} + ({} * {}) = {:X}",
-                end as usize, PAGE_SIZE, block_offset, dma_block
-            );
-        }
+        // the pool lock is released at the end of this statement, so the panic handler can report on it
+        let dma_block = DMA_POOL.lock().alloc(pages);
+        let dma_block =
+            dma_block.unwrap_or_else(|| panic!("unable to find {} contiguous DMA pages", pages));
+        let vaddr = paging::phys_to_virt(dma_block)
+            .and_then(|vaddr| NonNull::new(vaddr as _))
+            .expect("DMA pool page isn't mapped");
+        debug!("alloc DMA: paddr={:#x}, pages={}", dma_block, pages);
         (dma_block, vaddr)
     }
 
     unsafe fn dma_dealloc(paddr: PhysAddr, _vaddr: NonNull<u8>, pages: usize) -> i32 {
-        //#[cfg(debug_assertions)]
-        println!("dealloc {} {}", paddr, end as usize);
-        if paddr <= (end as usize) {
-            return 0;
+        if !DMA_POOL.lock().dealloc(paddr, pages) {
+            warn!("dealloc of DMA memory outside the pool: paddr={:#x}", paddr);
+            return -1;
         }
-        let offset = paddr - (end as usize);
-        let page = offset / PAGE_SIZE;
-        let negate = !(generate_mask(pages) >> page);
-        OPEN_PAGES.fetch_and(negate, Ordering::Relaxed);
-        ALLOC_PAGES.fetch_sub(pages as u8, Ordering::Relaxed);
         zero_out_memory(paddr as *mut u8, pages * PAGE_SIZE);
-        #[cfg(debug_assertions)]
-        println!("dealloc DMA: paddr={:#x}, pages={}", paddr, pages);
+        debug!("dealloc DMA: paddr={:#x}, pages={}", paddr, pages);
         0
     }
 
     unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
-        NonNull::new(paddr as _).unwrap()
+        // only the PCI transport calls this, with a BAR's bus address
+        let paddr = pci_enum::memory_cpu_address(paddr as u64)
+            .unwrap_or_else(|| panic!("BAR address {:#x} is outside the PCI windows", paddr));
+        let vaddr = paging::phys_to_virt(paddr as usize)
+            .unwrap_or_else(|| panic!("MMIO at {:#x} isn't mapped", paddr));
+        NonNull::new(vaddr as _).unwrap()
     }
 
     unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
         let vaddr = buffer.as_ptr() as *mut u8 as usize;
         // Nothing to do, as the host already has access to all memory.
+        // buffers never cross from one mapping into another, the kernel is identity mapped
         virt_to_phys(vaddr)
     }
 
@@ -125,5 +256,6 @@ unsafe impl Hal for HalImpl {
 }
 
 fn virt_to_phys(vaddr: usize) -> PhysAddr {
-    vaddr
+    paging::virt_to_phys(vaddr)
+        .unwrap_or_else(|| panic!("shared buffer at {:#x} isn't mapped", vaddr))
 }
@@ -0,0 +1,152 @@
+// virtio_irq.rs
+// interrupt driven completion for virtio requests, the cpu sleeps in `wfi` while a request is in flight
+#![allow(dead_code)]
+use crate::{
+    plic,
+    time::{self, Timeout},
+    virtio_hal::HalImpl,
+};
+use alloc::boxed::Box;
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use virtio_drivers::{
+    device::{
+        blk::{BlkReq, BlkResp, VirtIOBlk},
+        console::VirtIOConsole,
+    },
+    transport::Transport,
+};
+
+/// virtio-mmio InterruptStatus register
+const MMIO_INTERRUPT_STATUS: usize = 0x60;
+/// virtio-mmio InterruptACK register
+const MMIO_INTERRUPT_ACK: usize = 0x64;
+/// priority given to virtio interrupt ids
+const VIRTIO_PRIORITY: u8 = 1;
+
+/// how to tell a device we've seen its interrupt
+#[derive(Copy, Clone, Debug)]
+pub enum IrqAck {
+    /// base address of a virtio-mmio device, acked by writing InterruptACK
+    Mmio(usize),
+    /// address of a virtio-pci ISR status byte, acked by reading it
+    PciIsr(usize),
+}
+
+/// the interrupt state for one virtio device
+pub struct VirtioIrq {
+    irq: u32,
+    ack: IrqAck,
+    count: AtomicUsize,
+}
+
+impl VirtioIrq {
+    /// hook a device up to a PLIC interrupt id and enable it
+    pub fn register(irq: u32, ack: IrqAck) -> &'static Self {
+        let this: &'static Self = Box::leak(Box::new(Self {
+            irq,
+            ack,
+            count: AtomicUsize::new(0),
+        }));
+        plic::register_handler(irq, VIRTIO_PRIORITY, this);
+        this
+    }
+
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    /// number of interrupts this device has raised
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// sleep until `poll` returns something. interrupts are masked while polling so one
+    /// arriving between the check and the `wfi` still wakes us. if interrupts are off
+    /// altogether this degrades to spinning on `poll`
+    pub fn wait_until<T>(&self, poll: impl FnMut() -> Option<T>) -> T {
+        time::wait_until(None, poll).unwrap()
+    }
+
+    /// like `wait_until`, but give up once `timeout` has passed
+    pub fn wait_timeout<T>(&self, timeout: Duration, poll: impl FnMut() -> Option<T>) -> Option<T> {
+        time::wait_until(Some(Timeout::after(timeout)), poll)
+    }
+}
+
+impl plic::IrqHandler for VirtioIrq {
+    fn handle(&self, _id: u32) -> bool {
+        let ours = match self.ack {
+            IrqAck::Mmio(base) => unsafe {
+                let status = ((base + MMIO_INTERRUPT_STATUS) as *const u32).read_volatile();
+                if status != 0 {
+                    ((base + MMIO_INTERRUPT_ACK) as *mut u32).write_volatile(status);
+                }
+                status != 0
+            },
+            IrqAck::PciIsr(isr) => unsafe { (isr as *const u8).read_volatile() != 0 },
+        };
+        if ours {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+        ours
+    }
+}
+
+/// write blocks, sleeping until the device interrupts instead of spinning on the queue
+pub fn write_blocks<T: Transport>(
+    blk: &mut VirtIOBlk<HalImpl, T>,
+    irq: Option<&VirtioIrq>,
+    block_id: usize,
+    buf: &[u8],
+) -> virtio_drivers::Result {
+    let Some(irq) = irq else {
+        return blk.write_blocks(block_id, buf);
+    };
+    let mut req = BlkReq::default();
+    let mut resp = BlkResp::default();
+    unsafe {
+        let token = blk.write_blocks_nb(block_id, &mut req, buf, &mut resp)?;
+        irq.wait_until(|| (blk.peek_used() == Some(token)).then_some(()));
+        blk.complete_write_blocks(token, &req, buf, &mut resp)
+    }
+}
+
+/// read blocks, sleeping until the device interrupts instead of spinning on the queue
+pub fn read_blocks<T: Transport>(
+    blk: &mut VirtIOBlk<HalImpl, T>,
+    irq: Option<&VirtioIrq>,
+    block_id: usize,
+    buf: &mut [u8],
+) -> virtio_drivers::Result {
+    let Some(irq) = irq else {
+        return blk.read_blocks(block_id, buf);
+    };
+    let mut req = BlkReq::default();
+    let mut resp = BlkResp::default();
+    unsafe {
+        let token = blk.read_blocks_nb(block_id, &mut req, buf, &mut resp)?;
+        irq.wait_until(|| (blk.peek_used() == Some(token)).then_some(()));
+        blk.complete_read_blocks(token, &req, buf, &mut resp)
+    }
+}
+
+/// receive one byte from a virtio console, sleeping until one arrives
+pub fn recv<T: Transport>(
+    console: &mut VirtIOConsole<HalImpl, T>,
+    irq: &VirtioIrq,
+) -> virtio_drivers::Result<u8> {
+    irq.wait_until(|| console.recv(true).transpose())
+}
+
+/// receive one byte from a virtio console, giving up after `timeout`
+pub fn recv_timeout<T: Transport>(
+    console: &mut VirtIOConsole<HalImpl, T>,
+    irq: &VirtioIrq,
+    timeout: Duration,
+) -> virtio_drivers::Result<Option<u8>> {
+    irq.wait_timeout(timeout, || console.recv(true).transpose())
+        .transpose()
+}